@@ -1,59 +1,251 @@
 use core::str;
 use std::{
-    collections::{HashMap, HashSet},
-    fmt::Debug,
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt::{Debug, Display},
     path::{Component, Path, PathBuf},
+    sync::LazyLock,
 };
 
 use eros::{Context, bail};
-use indexmap::IndexMap;
+use indexmap::{IndexMap, IndexSet};
 use jsonschema::{Draft, Validator};
-use serde::Deserialize;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use similar::{DiffOp, TextDiff};
 use tera::Tera;
 use tokio::fs;
 use tracing::trace;
 
+use crate::diff;
+use crate::generated_files;
+use crate::include::resolve_includes;
+use crate::manifest::{self, ManifestFormat};
+use crate::module_file::{
+    BlockRole, ContainerfileBlock, MODULE_CONTAINERFILE_NAME, MODULE_INSTALL_CONTAINERFILE_NAME,
+    read_module,
+};
+use crate::provenance::{self, ProvenanceModule};
 use crate::remote_resolvers::{GitProvider, create_provider};
+use crate::staging;
+use crate::template_functions;
 
 pub const YARD_YAML_FILE_NAME: &str = "yard.yaml";
 
-pub async fn build(
-    path: &Path,
-    do_not_refetch: bool,
-    with_cache_busting: bool,
-) -> eros::Result<()> {
-    let (parsed_yard_file, post_build_hook) = parse_yard_yaml(path).await?;
-    let resolved_yard_file = resolve_yard_yaml(parsed_yard_file, path, do_not_refetch).await?;
-    if resolved_yard_file.name_to_module.is_empty() {
-        bail!("No modules were resolved.")
+/// Options of a build, shared by the commands that render the outputs.
+pub struct BuildOptions<'a> {
+    /// Yard file to use instead of the `yard.yaml` file in the path
+    pub yard_file: Option<&'a Path>,
+    /// Overrides the `output_dir` of the yard file
+    pub out_dir: Option<&'a Path>,
+    pub do_not_refetch: bool,
+    pub with_cache_busting: bool,
+    pub profile: Option<&'a str>,
+    /// Also render the manifest describing what went into each output
+    pub emit_manifest: Option<ManifestFormat>,
+    /// Run the pre-build hook of the yard file. Commands that only preview the build skip it, since
+    /// the hook may write files
+    pub run_pre_build_hook: bool,
+}
+
+/// Everything a build writes, rendered in memory.
+pub struct RenderedBuild {
+    /// Directory the outputs and required files are written to
+    pub output_dir: PathBuf,
+    pub outputs: Outputs,
+    pub required_files: RequiredFiles,
+    /// `.yardmap` path, relative to the output directory, and content of each output. Empty
+    /// unless enabled with `provenance.source_map`
+    pub source_maps: Vec<(PathBuf, String)>,
+    /// Content of the manifest, if requested with `--emit-manifest`
+    pub manifest: Option<String>,
+    pub post_build_hook: Option<String>,
+    /// Name of the manifest of the files generated by the yard file, in the output directory
+    pub generated_files_name: String,
+    /// Paths of the outputs the selected profile leaves out, relative to the output directory
+    pub unselected_outputs: Vec<PathBuf>,
+}
+
+impl RenderedBuild {
+    /// Path, relative to the output directory, and content of every file the build writes. Required
+    /// files come first, so they are in place before the outputs referencing them.
+    pub fn files(&self) -> Vec<(&Path, &[u8])> {
+        let required_files = self
+            .required_files
+            .iter()
+            .map(|(file_path, content)| (file_path.as_path(), content.as_slice()));
+        let outputs = self
+            .outputs
+            .iter()
+            .map(|output| (output.path.as_path(), output.content.as_bytes()));
+        let source_maps = self
+            .source_maps
+            .iter()
+            .map(|(file_path, content)| (file_path.as_path(), content.as_bytes()));
+        let manifest = self
+            .manifest
+            .iter()
+            .map(|manifest| (Path::new(manifest::MANIFEST_FILE_NAME), manifest.as_bytes()));
+        required_files
+            .chain(outputs)
+            .chain(source_maps)
+            .chain(manifest)
+            .collect()
     }
-    let outputs = apply_templating(resolved_yard_file, with_cache_busting)?;
-    if outputs.is_empty() {
-        bail!("No Containerfiles where created.")
+}
+
+pub async fn build(path: &Path, options: &BuildOptions<'_>, check: bool) -> eros::Result<()> {
+    let rendered = render(path, options).await?;
+    if check {
+        return diff::check(&rendered);
     }
-    for (file_name, content) in outputs {
-        let file_path = path.join(&file_name);
-        fs::write(&file_path, content)
-            .await
-            .with_context(|| format!("Could not write to '{}'.", &file_name))?;
+    let mut files = rendered.files();
+    let output_dir = &rendered.output_dir;
+    // Files of the previous build of the yard file that this build does not produce are removed,
+    // unless another yard file generates them too. While the profile leaves outputs out they are
+    // kept, since they may belong to those outputs
+    let previously_generated =
+        generated_files::read(output_dir, &rendered.generated_files_name).await?;
+    let generated_by_others =
+        generated_files::read_others(output_dir, &rendered.generated_files_name).await?;
+    let (no_longer_generated, kept): (Vec<&Path>, Vec<&Path>) = previously_generated
+        .iter()
+        .map(PathBuf::as_path)
+        .filter(|file_path| files.iter().all(|(generated, _)| generated != file_path))
+        .filter(|file_path| !generated_by_others.iter().any(|other| other == file_path))
+        .partition(|_| rendered.unselected_outputs.is_empty());
+    let generated_file_paths: Vec<&Path> = files
+        .iter()
+        .map(|(file_path, _)| *file_path)
+        .chain(kept)
+        .collect();
+    let manifest = generated_files::render(&generated_file_paths);
+    files.push((
+        Path::new(&rendered.generated_files_name),
+        manifest.as_bytes(),
+    ));
+    staging::write_all(output_dir, &files, &no_longer_generated).await?;
+    for file_path in no_longer_generated {
+        generated_files::remove_empty_parents(output_dir, file_path).await;
+        println!(
+            "Removed '{}', it is no longer generated",
+            output_dir.join(file_path).display()
+        );
+    }
+    for output in &rendered.outputs {
         println!(
             "Created '{}' at '{}",
-            &file_name,
-            &file_path
+            output.path.display(),
+            output_dir
+                .join(&output.path)
                 .canonicalize()
                 .expect("Could not get absolute path.")
                 .display()
         );
     }
 
-    if let Some(post_build_hook) = post_build_hook {
-        duct_sh::sh_dangerous(&post_build_hook)
+    if let Some(post_build_hook) = &rendered.post_build_hook {
+        duct_sh::sh_dangerous(post_build_hook)
             .run()
             .with_context(|| format!("Post-build hook `{post_build_hook}` Failed"))?;
     }
     Ok(())
 }
 
+/// Runs the whole build pipeline without writing the outputs or required files.
+pub async fn render(path: &Path, options: &BuildOptions<'_>) -> eros::Result<RenderedBuild> {
+    let yard_file_path = yard_file_path(path, options.yard_file);
+    let (parsed_yard_file, post_build_hook) =
+        parse_yard_yaml(&yard_file_path, options.profile, options.run_pre_build_hook).await?;
+    let output_dir = output_dir(
+        path,
+        &yard_file_path,
+        options.out_dir,
+        parsed_yard_file.output_dir.as_deref(),
+    );
+    let unselected_outputs = parsed_yard_file.unselected_outputs.clone();
+    let (resolved_yard_file, required_files) =
+        resolve_yard_yaml(parsed_yard_file, path, &output_dir, options.do_not_refetch).await?;
+    if resolved_yard_file.name_to_module.is_empty() {
+        bail!("No modules were resolved.")
+    }
+    let with_source_maps = resolved_yard_file.provenance.source_map == Some(true);
+    let outputs = apply_templating(
+        resolved_yard_file,
+        &output_dir,
+        &required_files,
+        options.with_cache_busting,
+    )?;
+    if outputs.is_empty() {
+        bail!("No Containerfiles where created.")
+    }
+    let source_maps = if with_source_maps {
+        outputs.iter().map(provenance::source_map).collect()
+    } else {
+        Vec::new()
+    };
+    let manifest = match options.emit_manifest {
+        Some(ManifestFormat::Json) => {
+            Some(manifest::render(&outputs, &required_files, &output_dir, options.profile).await?)
+        }
+        None => None,
+    };
+    Ok(RenderedBuild {
+        output_dir,
+        outputs,
+        required_files,
+        source_maps,
+        manifest,
+        post_build_hook,
+        generated_files_name: generated_files::file_name(&yard_file_path),
+        unselected_outputs,
+    })
+}
+
+/// The yard file selected with `--file`, otherwise the `yard.yaml` file in `path`.
+pub fn yard_file_path(path: &Path, yard_file: Option<&Path>) -> PathBuf {
+    yard_file.map_or_else(|| path.join(YARD_YAML_FILE_NAME), Path::to_path_buf)
+}
+
+/// The output directory of the yard file, without building.
+pub async fn resolve_output_dir(
+    path: &Path,
+    yard_file: Option<&Path>,
+    out_dir: Option<&Path>,
+) -> eros::Result<PathBuf> {
+    if let Some(out_dir) = out_dir {
+        return Ok(out_dir.to_path_buf());
+    }
+    let yard_file_path = yard_file_path(path, yard_file);
+    let yard_yaml = load_yard_file(&yard_validator(), &yard_file_path).await?;
+    Ok(output_dir(
+        path,
+        &yard_file_path,
+        None,
+        yard_yaml.output_dir.as_deref(),
+    ))
+}
+
+/// The directory outputs are written to and required files are placed in. `--out-dir` takes
+/// precedence over the `output_dir` of the yard file, which is relative to the yard file's
+/// directory. Defaults to `path`.
+fn output_dir(
+    path: &Path,
+    yard_file_path: &Path,
+    out_dir: Option<&Path>,
+    yard_output_dir: Option<&str>,
+) -> PathBuf {
+    if let Some(out_dir) = out_dir {
+        return out_dir.to_path_buf();
+    }
+    match yard_output_dir {
+        Some(yard_output_dir) => yard_file_path
+            .parent()
+            .unwrap_or(Path::new(""))
+            .join(yard_output_dir),
+        None => path.to_path_buf(),
+    }
+}
+
 // Deserialized module config
 //************************************************************************//
 /// Created using the yard-module-schema.json file and https://app.quicktype.io/
@@ -65,11 +257,17 @@ pub struct YamlModule {
     /// If true, this module is an independent module. Independent modules have a build stage
     /// and an install stage, defined by two containerfile/dockerfile blocks. The build stage is
     /// hoisted to the start of the generated Containerfile and the install stage is injected where
-    /// the module is declared. Defaults to false.
+    /// the module is declared. Both stages can use the `{{ stage }}` template variable, a stage
+    /// name unique to the module instance. Not needed when blocks declare roles (`stage=<name>`,
+    /// `install`, `final`). Defaults to false.
     #[serde(default)]
     pub independent: bool,
     /// List of required files for the module. Must be absolution paths from the current directory without a starting "/"
     pub required_files: Option<Vec<String>>,
+    /// List of Tera template files the module can `{% include %}` and use the components of. Paths
+    /// are relative to the root of the module's source - the repo for remote modules, the current
+    /// directory for local modules.
+    pub templates: Option<Vec<String>>,
 }
 
 #[derive(Debug, Clone, PartialEq, Default, Deserialize)]
@@ -85,9 +283,45 @@ pub struct YamlArgs {
 #[derive(Debug, Clone, PartialEq, Deserialize)]
 pub struct YamlYard {
     pub hooks: Option<YamlHooks>,
+    /// Directory outputs are written to, relative to the yard file
+    pub output_dir: Option<String>,
+    /// Var name to value. Resolved once per build
+    pub vars: Option<IndexMap<String, String>>,
     pub inputs: YamlInputs,
     /// Containerfile name to config
-    pub outputs: IndexMap<String, Vec<YamlModuleType>>,
+    pub outputs: IndexMap<String, YamlOutput>,
+    /// Profile name to overrides
+    pub profiles: Option<IndexMap<String, YamlProfile>>,
+    pub provenance: Option<YamlProvenance>,
+    /// Module name to the directory of the included yard file declaring the local module. Set by
+    /// [resolve_includes]
+    #[serde(skip)]
+    pub module_roots: HashMap<String, PathBuf>,
+}
+
+/// What is recorded about where the lines of each output come from
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct YamlProvenance {
+    /// Start each output with a header listing the yard version, remotes and resolved args
+    pub header: Option<bool>,
+    /// Write a `.yardmap` source map next to each output
+    pub source_map: Option<bool>,
+    /// Append a `LABEL` instruction with the OCI and yard labels to the final stage of each output
+    pub labels: Option<bool>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct YamlProfile {
+    /// Overrides the vars with the same name
+    pub vars: Option<IndexMap<String, String>>,
+    /// Module name to args. Overrides the args of every entry of the module
+    pub args: Option<HashMap<String, HashMap<String, String>>>,
+    /// Overrides the declared hooks
+    pub hooks: Option<YamlHooks>,
+    /// Only these outputs are created
+    pub outputs: Option<Vec<String>>,
 }
 
 #[derive(Debug, Clone, PartialEq, Deserialize)]
@@ -119,9 +353,106 @@ pub struct YamlRemote {
 pub enum YamlModuleType {
     /// Inline `- Run ...`
     Inline(String),
+    /// Inline with template values `- inline: Run ...`
+    InlineWithArgs(YamlInlineModule),
     /// Module ref `- module_name:`
     /// Module ref with template values `- module_name: ...`
-    InputRef(IndexMap<String, Option<HashMap<String, String>>>),
+    InputRef(YamlInputRef),
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct YamlInputRef {
+    /// Condition for including the module
+    pub when: Option<String>,
+    /// Module name to template values
+    #[serde(flatten)]
+    pub module: IndexMap<String, Option<HashMap<String, String>>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(untagged)]
+pub enum YamlOutput {
+    /// Module entries of the output
+    Modules(Vec<YamlModuleType>),
+    /// Module entries of another output, with changes applied
+    Extends(YamlOutputExtension),
+    /// An output per combination of matrix values
+    Matrix(YamlMatrixOutput),
+    /// Module entries of the output, with its own build context
+    Context(YamlContextOutput),
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct YamlContextOutput {
+    /// Build context directory of the output, relative to the output directory
+    pub context: String,
+    pub modules: Vec<YamlModuleType>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct YamlMatrixOutput {
+    /// Matrix key to values. The output name is rendered with the values of each combination
+    pub matrix: IndexMap<String, Vec<serde_yaml::Value>>,
+    /// Build context directory of each output, rendered with the values of each combination
+    pub context: Option<String>,
+    pub modules: Vec<YamlModuleType>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct YamlOutputExtension {
+    /// Name of the output to extend
+    pub extends: String,
+    /// Applied in order to the module entries of the extended output
+    pub changes: Option<Vec<YamlOutputChange>>,
+    /// Build context directory of the output. Not inherited from the extended output
+    pub context: Option<String>,
+}
+
+/// A change to a module entry of an extended output. Entries are referenced by module name.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(untagged, deny_unknown_fields)]
+pub enum YamlOutputChange {
+    /// `- replace: module_name` with `with: [...]`
+    Replace {
+        replace: String,
+        with: Vec<YamlModuleType>,
+    },
+    /// `- remove: module_name`
+    Remove { remove: String },
+    /// `- before: module_name` with `insert: [...]`
+    InsertBefore {
+        before: String,
+        insert: Vec<YamlModuleType>,
+    },
+    /// `- after: module_name` with `insert: [...]`
+    InsertAfter {
+        after: String,
+        insert: Vec<YamlModuleType>,
+    },
+}
+
+impl YamlModuleType {
+    /// The name of the referenced module. `None` for inline modules.
+    fn module_name(&self) -> Option<&str> {
+        match self {
+            YamlModuleType::InputRef(module_ref) => {
+                module_ref.module.keys().next().map(String::as_str)
+            }
+            YamlModuleType::Inline(_) | YamlModuleType::InlineWithArgs(_) => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct YamlInlineModule {
+    pub inline: String,
+    pub args: Option<HashMap<String, String>>,
+    /// Condition for including the module
+    pub when: Option<String>,
 }
 
 // Intermediate  yard.yaml reprsentation
@@ -129,11 +460,34 @@ pub enum YamlModuleType {
 
 #[derive(Debug, Clone, Default)]
 struct YardFile {
+    /// Name of the selected profile
+    profile: Option<String>,
+    /// Directory outputs are written to, relative to the yard file
+    output_dir: Option<String>,
+    provenance: YamlProvenance,
+    /// Var name to unresolved value
+    vars: IndexMap<String, String>,
     input_remotes: Vec<RemoteModules>,
     /// Module name to path on local
     input_modules: HashMap<String, String>,
+    /// Module name to the directory its required files and templates are relative to, for local
+    /// modules of included yard files
+    input_module_roots: HashMap<String, PathBuf>,
     /// Containerfile name to included modules
-    output_container_files: IndexMap<String, Vec<UseModule>>,
+    output_container_files: IndexMap<String, UseOutput>,
+    /// Paths of the outputs the selected profile leaves out, relative to the output directory
+    unselected_outputs: Vec<PathBuf>,
+}
+
+/// A Containerfile to output
+#[derive(Debug, Clone, Default)]
+struct UseOutput {
+    /// Matrix key to value of the combination this output was generated from. Empty if the output
+    /// does not declare a matrix
+    matrix_values: IndexMap<String, String>,
+    /// Build context directory, relative to the output directory. `None` for the output directory
+    context: Option<String>,
+    modules: Vec<UseModule>,
 }
 
 /// Reference to a remote and containing modules
@@ -152,9 +506,12 @@ enum UseModule {
 }
 
 /// Inline module
-#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
 struct UseInlineModule {
     value: String,
+    template_vars: HashMap<String, String>,
+    /// Tera expression, the module is only included if it is true
+    when: Option<String>,
 }
 
 /// Reference to an input module
@@ -162,6 +519,49 @@ struct UseInlineModule {
 struct UseInputModule {
     name: String,
     template_vars: HashMap<String, String>,
+    /// Tera expression, the module is only included if it is true
+    when: Option<String>,
+}
+
+impl UseModule {
+    fn when(&self) -> Option<&str> {
+        match self {
+            UseModule::Inline(inline) => inline.when.as_deref(),
+            UseModule::Input(input) => input.when.as_deref(),
+        }
+    }
+
+    /// The name of the referenced input module. `None` for inline modules.
+    fn input_name(&self) -> Option<&str> {
+        match self {
+            UseModule::Inline(_) => None,
+            UseModule::Input(input) => Some(&input.name),
+        }
+    }
+}
+
+/// A module entry excluded from an output by its `when:` condition.
+pub struct SkippedModule {
+    output: String,
+    module: UseModule,
+}
+
+impl Display for SkippedModule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let when = self.module.when().unwrap_or_default();
+        match &self.module {
+            UseModule::Inline(inline) => write!(
+                f,
+                "Skipped inline module `{}` in '{}', `when: {}` is false.",
+                inline.value, self.output, when
+            ),
+            UseModule::Input(input) => write!(
+                f,
+                "Skipped module '{}' in '{}', `when: {}` is false.",
+                input.name, self.output, when
+            ),
+        }
+    }
 }
 
 //************************************************************************//
@@ -169,12 +569,17 @@ struct UseInputModule {
 /// Builder for when constructing all the values needed to operate on the template
 #[derive(Debug, Clone)]
 struct ModuleBuilder {
-    containerfile_data: String,
-    /// Install stage template for independent modules. `None` for non-independent modules.
-    install_stage_data: Option<String>,
-    /// Whether this module is an independent module.
-    independent: bool,
+    /// Stages hoisted to the start of the Containerfile.
+    stage_data: Vec<ContainerfileBlock>,
+    /// Injected where the module is declared. `None` if the module only has stages or finalizers.
+    containerfile_data: Option<ContainerfileBlock>,
+    /// Appended to the end of the Containerfile.
+    final_data: Vec<ContainerfileBlock>,
     required_files: Vec<String>,
+    /// Paths of the template libraries declared in `templates`.
+    template_paths: Vec<String>,
+    /// Template library path to content. Resolved from `template_paths`.
+    template_libraries: Vec<(String, String)>,
     required_template_values: HashSet<String>,
     optional_template_values: HashSet<String>,
     provided_template_values: HashMap<String, String>,
@@ -206,18 +611,34 @@ impl ModuleBuilder {
                 );
             }
         }
-        // This is not necessary at this point, as this should have already been checked. But kept just to make sure.
-        validate_path_references(&self.required_files)?;
-        if self.independent && self.install_stage_data.is_none() {
+        if self.required_template_values.contains(YARD_TEMPLATE_VAR)
+            || self.optional_template_values.contains(YARD_TEMPLATE_VAR)
+        {
             bail!(
-                "Module is marked as independent (`independent: true`), but no install stage was found. Independent modules require two containerfile/dockerfile blocks - the first is the build stage and the second is the install stage.\n{}",
+                "Modules may not declare an arg named '{}', it is provided by yard.\n{}",
+                YARD_TEMPLATE_VAR,
                 self.source_info.source_location()
             );
         }
+        if !self.stage_data.is_empty() {
+            for reserved in [STAGE_TEMPLATE_VAR, STAGES_TEMPLATE_VAR] {
+                if self.required_template_values.contains(reserved)
+                    || self.optional_template_values.contains(reserved)
+                {
+                    bail!(
+                        "Modules with stages may not declare an arg named '{}', it is provided by yard.\n{}",
+                        reserved,
+                        self.source_info.source_location()
+                    );
+                }
+            }
+        }
         Ok(Module {
+            stage_templates: self.stage_data,
             containerfile_template: self.containerfile_data,
-            install_stage_template: self.install_stage_data,
-            independent: self.independent,
+            final_templates: self.final_data,
+            template_libraries: self.template_libraries,
+            required_files: self.required_files,
             provided_template_values: self.provided_template_values,
             source_info: self.source_info,
             name: self.name,
@@ -230,18 +651,31 @@ impl ModuleBuilder {
 
 /// All containerfile and their resolved modules. Ready to apply
 struct Containerfiles {
+    /// Name of the selected profile
+    profile: Option<String>,
+    provenance: YamlProvenance,
+    /// Resolved vars of the `yard.yaml` file, available to inline modules
+    vars: IndexMap<String, String>,
     /// Containerfile names to included modules
     name_to_module: IndexMap<String, Vec<Module>>,
+    /// Containerfile names to build context directory, relative to the output directory. Outputs
+    /// without a context are in the output directory
+    name_to_context: HashMap<String, String>,
 }
 
 /// The template Containerfile and config combined. Ready to apply
 #[derive(Debug, Clone)]
 struct Module {
-    containerfile_template: String,
-    /// Install stage template for independent modules. `None` for non-independent modules.
-    install_stage_template: Option<String>,
-    /// Whether this module is an independent module.
-    independent: bool,
+    /// Stages hoisted to the start of the Containerfile.
+    stage_templates: Vec<ContainerfileBlock>,
+    /// Injected where the module is declared.
+    containerfile_template: Option<ContainerfileBlock>,
+    /// Appended to the end of the Containerfile.
+    final_templates: Vec<ContainerfileBlock>,
+    /// Template library path to content, available to `{% include %}`.
+    template_libraries: Vec<(String, String)>,
+    /// Required files, relative to the build context of the output.
+    required_files: Vec<String>,
     provided_template_values: HashMap<String, String>,
     /// source info for better errors
     source_info: SourceInfoKind,
@@ -249,10 +683,14 @@ struct Module {
     name: Option<String>,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Default, Serialize)]
 pub struct LocalModuleInfo {
     pub path: String,
     pub name: String,
+    /// Directory the required files and templates of the module are relative to. Empty for the
+    /// current directory, the directory of the included yard file for its modules
+    #[serde(skip)]
+    pub root: PathBuf,
 }
 
 impl SourceInfo for LocalModuleInfo {
@@ -261,7 +699,7 @@ impl SourceInfo for LocalModuleInfo {
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Default, Serialize)]
 pub struct RemoteModuleInfo {
     /// original url
     pub url: String,
@@ -282,7 +720,7 @@ impl SourceInfo for RemoteModuleInfo {
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Default, Serialize)]
 pub struct InlineModuleInfo {
     pub value: String,
 }
@@ -298,7 +736,8 @@ trait SourceInfo {
 }
 
 /// Info about where data came from.
-#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
 pub enum SourceInfoKind {
     Local(LocalModuleInfo),
     Remote(RemoteModuleInfo),
@@ -328,10 +767,12 @@ impl SourceInfo for SourceInfoKind {
 //************************************************************************//
 
 pub struct ModuleFileData {
-    pub containerfile_data: String,
+    pub containerfile_blocks: Vec<ContainerfileBlock>,
     pub config_data: String,
-    /// Install stage for independent modules. `None` for non-independent modules.
-    pub install_stage_data: Option<String>,
+    /// Line of the config block in the module file. `None` if there is no config block.
+    pub config_line: Option<usize>,
+    /// File the config is read from.
+    pub config_path: PathBuf,
     pub source_info: SourceInfoKind,
 }
 
@@ -344,17 +785,71 @@ async fn load_yard_file(
         .with_context(|| format!("Could read '{}'.", yard_file_path.display()))?;
     let yard_yaml: serde_yaml::Value = serde_yaml::from_str(&yard_yaml_file_data)
         .with_context(|| format!("{} is not valid yaml.", yard_file_path.display()))?;
+    let (yard_yaml, module_roots) = resolve_includes(yard_yaml, yard_file_path)
+        .await
+        .with_context(|| {
+            format!(
+                "Could not resolve the includes of '{}'.",
+                yard_file_path.display()
+            )
+        })?;
     validate_against_schema(compiled_schema, &yard_yaml)
         .with_context(|| format!("For path '{}'.", &yard_file_path.display()))?;
-    let yard_yaml: YamlYard = serde_yaml::from_value(yard_yaml).with_context(|| {
+    let mut yard_yaml: YamlYard = serde_yaml::from_value(yard_yaml).with_context(|| {
         format!(
             "Was able to serialize '{}', but was unable to convert to internal expected model.",
             yard_file_path.display()
         )
     })?;
+    yard_yaml.module_roots = module_roots;
     Ok(yard_yaml)
 }
 
+/// Loads the yard file and merges in the vars and hooks of the selected profile. The profile's args
+/// and outputs are applied by [to_yard_file].
+async fn load_yard_file_with_profile(
+    compiled_schema: &Validator,
+    yard_file_path: &Path,
+    profile: Option<&str>,
+) -> eros::Result<(YamlYard, Option<(String, YamlProfile)>)> {
+    let mut yard_yaml = load_yard_file(compiled_schema, yard_file_path).await?;
+    let Some(profile_name) = profile else {
+        return Ok((yard_yaml, None));
+    };
+    let profile = yard_yaml
+        .profiles
+        .as_ref()
+        .and_then(|profiles| profiles.get(profile_name))
+        .cloned()
+        .ok_or_else(|| {
+            eros::error!(
+                "Profile '{}' is not declared in '{}'.",
+                profile_name,
+                yard_file_path.display()
+            )
+        })?;
+    if let Some(vars) = &profile.vars {
+        yard_yaml
+            .vars
+            .get_or_insert_default()
+            .extend(vars.iter().map(|(name, val)| (name.clone(), val.clone())));
+    }
+    if let Some(profile_hooks) = &profile.hooks {
+        match &mut yard_yaml.hooks {
+            Some(hooks) => {
+                if profile_hooks.build.pre.is_some() {
+                    hooks.build.pre = profile_hooks.build.pre.clone();
+                }
+                if profile_hooks.build.post.is_some() {
+                    hooks.build.post = profile_hooks.build.post.clone();
+                }
+            }
+            None => yard_yaml.hooks = Some(profile_hooks.clone()),
+        }
+    }
+    Ok((yard_yaml, Some((profile_name.to_owned(), profile))))
+}
+
 fn yard_validator() -> Validator {
     let yard_schema: &'static str = include_str!("./schemas/yard-schema.json");
     let yard_schema: serde_json::Value =
@@ -365,30 +860,51 @@ fn yard_validator() -> Validator {
         .expect("yard-schema.json is not a valid json schema")
 }
 
-pub async fn output_order(path: &Path) -> eros::Result<Vec<String>> {
-    let yard_file_path = path.join(YARD_YAML_FILE_NAME);
+pub async fn output_order(
+    yard_file_path: &Path,
+    profile: Option<&str>,
+) -> eros::Result<Vec<String>> {
     let validator = yard_validator();
-    let yard_yaml = load_yard_file(&validator, &yard_file_path).await?;
-    Ok(yard_yaml.outputs.keys().cloned().collect())
+    let (yard_yaml, profile) =
+        load_yard_file_with_profile(&validator, yard_file_path, profile).await?;
+    let yard_file = to_yard_file(yard_yaml, profile)?;
+    Ok(yard_file.output_container_files.into_keys().collect())
 }
 
 /// parse yard.yaml and validate that all referenced modules are declared
-#[eros::context("Could not parse '{}'.", YARD_YAML_FILE_NAME)]
-async fn parse_yard_yaml(path: &Path) -> eros::Result<(YardFile, Option<String>)> {
+#[eros::context("Could not parse '{}'.", yard_file_path.display())]
+async fn parse_yard_yaml(
+    yard_file_path: &Path,
+    profile: Option<&str>,
+    run_pre_build_hook: bool,
+) -> eros::Result<(YardFile, Option<String>)> {
     let validator = yard_validator();
-    let yard_file_path = path.join(YARD_YAML_FILE_NAME);
-    let mut yard_yaml = load_yard_file(&validator, &yard_file_path).await?;
+    let (mut yard_yaml, mut selected_profile) =
+        load_yard_file_with_profile(&validator, yard_file_path, profile).await?;
     let pre_build_hook: Option<&str> = (|| yard_yaml.hooks.as_ref()?.build.pre.as_deref())();
-    if let Some(pre_build_hook) = pre_build_hook {
+    if let Some(pre_build_hook) = pre_build_hook
+        && run_pre_build_hook
+    {
         duct_sh::sh_dangerous(pre_build_hook)
             .run()
             .with_context(|| format!("Pre-build hook `{pre_build_hook}` Failed"))?;
         // We need to reload in case the pre-build hook updates the file
-        yard_yaml = load_yard_file(&validator, &yard_file_path)
-            .await
-            .context("First load of yard file succeeded, second load failed")?;
+        (yard_yaml, selected_profile) =
+            load_yard_file_with_profile(&validator, yard_file_path, profile)
+                .await
+                .context("First load of yard file succeeded, second load failed")?;
     }
 
+    let post_build_hook: Option<String> = (|| yard_yaml.hooks.as_ref()?.build.post.clone())();
+    Ok((to_yard_file(yard_yaml, selected_profile)?, post_build_hook))
+}
+
+/// Converts the deserialized yard.yaml into its intermediate representation, applying the args and
+/// outputs of the selected profile
+fn to_yard_file(
+    yard_yaml: YamlYard,
+    profile: Option<(String, YamlProfile)>,
+) -> eros::Result<YardFile> {
     let mut input_remotes: Vec<RemoteModules> = Vec::new();
     if let Some(remotes) = yard_yaml.inputs.remotes {
         for remote in remotes {
@@ -400,39 +916,403 @@ async fn parse_yard_yaml(path: &Path) -> eros::Result<(YardFile, Option<String>)
         }
     }
     let input_modules = yard_yaml.inputs.modules.unwrap_or_default();
-    let mut output_container_files: IndexMap<String, Vec<UseModule>> = IndexMap::new();
-    for (containerfile_name, output) in yard_yaml.outputs {
+    let input_module_roots = yard_yaml.module_roots;
+    let vars = yard_yaml.vars.unwrap_or_default();
+    let output_dir = yard_yaml.output_dir;
+    let provenance = yard_yaml.provenance.unwrap_or_default();
+    let mut output_container_files: IndexMap<String, UseOutput> = IndexMap::new();
+    for (containerfile_name, output) in expand_outputs(&yard_yaml.outputs)? {
         let mut modules: Vec<UseModule> = Vec::new();
-        for module in output {
+        for module in output.modules {
             match module {
                 YamlModuleType::Inline(value) => {
-                    modules.push(UseModule::Inline(UseInlineModule { value }));
+                    modules.push(UseModule::Inline(UseInlineModule {
+                        value,
+                        template_vars: HashMap::new(),
+                        when: None,
+                    }));
+                }
+                YamlModuleType::InlineWithArgs(inline) => {
+                    modules.push(UseModule::Inline(UseInlineModule {
+                        value: inline.inline,
+                        template_vars: inline.args.unwrap_or_default(),
+                        when: inline.when,
+                    }));
                 }
                 YamlModuleType::InputRef(module_ref) => {
-                    assert!(
-                        module_ref.len() <= 1,
-                        "Internal model is wrong. This should be `- module_name: ...`"
-                    );
-                    for (module_name, template_vars) in module_ref {
+                    if module_ref.module.len() != 1 {
+                        bail!(
+                            "An entry of the output '{}' should reference exactly one module, found '{}'.",
+                            containerfile_name,
+                            module_ref
+                                .module
+                                .keys()
+                                .cloned()
+                                .collect::<Vec<_>>()
+                                .join("', '")
+                        );
+                    }
+                    for (module_name, template_vars) in module_ref.module {
                         modules.push(UseModule::Input(UseInputModule {
                             name: module_name,
                             template_vars: template_vars.unwrap_or_default(),
+                            when: module_ref.when.clone(),
                         }));
                     }
                 }
             };
         }
-        output_container_files.insert(containerfile_name, modules);
+        output_container_files.insert(
+            containerfile_name,
+            UseOutput {
+                matrix_values: output.matrix_values,
+                context: output.context,
+                modules,
+            },
+        );
     }
-    let post_build_hook: Option<String> = (|| yard_yaml.hooks?.build.post)();
-    Ok((
-        YardFile {
+    let Some((profile_name, profile)) = profile else {
+        return Ok(YardFile {
+            profile: None,
+            output_dir,
+            provenance,
+            vars,
             input_remotes,
             input_modules,
+            input_module_roots,
             output_container_files,
+            unselected_outputs: Vec::new(),
+        });
+    };
+    for (module_name, args) in profile.args.unwrap_or_default() {
+        let is_declared = input_modules.contains_key(&module_name)
+            || input_remotes
+                .iter()
+                .any(|remote| remote.name_to_path.contains_key(&module_name));
+        if !is_declared {
+            bail!(
+                "Profile '{}' declares args for module '{}', but it is not declared as an input.",
+                profile_name,
+                module_name
+            );
+        }
+        for module in output_container_files
+            .values_mut()
+            .flat_map(|output| output.modules.iter_mut())
+        {
+            if let UseModule::Input(module) = module
+                && module.name == module_name
+            {
+                module.template_vars.extend(args.clone());
+            }
+        }
+    }
+    let mut unselected_outputs = Vec::new();
+    if let Some(selected_outputs) = profile.outputs {
+        for output_name in &selected_outputs {
+            if !output_container_files.contains_key(output_name) {
+                bail!(
+                    "Profile '{}' selects the output '{}', but it is not declared.",
+                    profile_name,
+                    output_name
+                );
+            }
+        }
+        unselected_outputs = output_container_files
+            .iter()
+            .filter(|(output_name, _)| !selected_outputs.contains(output_name))
+            .map(|(output_name, output)| {
+                Path::new(output.context.as_deref().unwrap_or_default()).join(output_name)
+            })
+            .collect();
+        output_container_files.retain(|output_name, _| selected_outputs.contains(output_name));
+    }
+    Ok(YardFile {
+        profile: Some(profile_name),
+        output_dir,
+        provenance,
+        vars,
+        input_remotes,
+        input_modules,
+        input_module_roots,
+        output_container_files,
+        unselected_outputs,
+    })
+}
+
+/// An output with `extends:` and `matrix:` expanded.
+struct ExpandedOutput {
+    /// Matrix key to value of the combination this output was generated from.
+    matrix_values: IndexMap<String, String>,
+    /// Build context directory, relative to the output directory
+    context: Option<String>,
+    modules: Vec<YamlModuleType>,
+}
+
+/// Expands outputs that `extends:` another output into their module entries, and outputs with a
+/// `matrix:` into an output per combination. Combinations are ordered with the first matrix key
+/// changing slowest.
+fn expand_outputs(
+    outputs: &IndexMap<String, YamlOutput>,
+) -> eros::Result<IndexMap<String, ExpandedOutput>> {
+    fn expand<'a>(
+        name: &'a str,
+        outputs: &'a IndexMap<String, YamlOutput>,
+        extending: &mut Vec<&'a str>,
+    ) -> eros::Result<Vec<YamlModuleType>> {
+        let output = outputs
+            .get(name)
+            .ok_or_else(|| eros::error!("Output '{}' does not exist.", name))?;
+        let extension = match output {
+            YamlOutput::Modules(modules)
+            | YamlOutput::Context(YamlContextOutput { modules, .. }) => {
+                return Ok(modules.clone());
+            }
+            YamlOutput::Extends(extension) => extension,
+            YamlOutput::Matrix(_) => {
+                bail!("Output '{}' has a matrix and cannot be extended.", name)
+            }
+        };
+        if extending.contains(&name) {
+            extending.push(name);
+            bail!(
+                "Outputs extend each other in a cycle: {}.",
+                extending.join(" -> ")
+            );
+        }
+        extending.push(name);
+        let mut modules = expand(&extension.extends, outputs, extending).with_context(|| {
+            format!(
+                "Output '{}' could not extend '{}'.",
+                name, extension.extends
+            )
+        })?;
+        extending.pop();
+        let position = |modules: &[YamlModuleType], module_name: &str| {
+            modules
+                .iter()
+                .position(|module| module.module_name() == Some(module_name))
+                .ok_or_else(|| {
+                    eros::error!(
+                        "Output '{}' changes module '{}', but it is not declared in the extended output '{}'.",
+                        name,
+                        module_name,
+                        extension.extends
+                    )
+                })
+        };
+        for change in extension.changes.iter().flatten() {
+            match change {
+                YamlOutputChange::Replace { replace, with } => {
+                    let index = position(&modules, replace)?;
+                    modules.splice(index..=index, with.iter().cloned());
+                }
+                YamlOutputChange::Remove { remove } => {
+                    let index = position(&modules, remove)?;
+                    modules.remove(index);
+                }
+                YamlOutputChange::InsertBefore { before, insert } => {
+                    let index = position(&modules, before)?;
+                    modules.splice(index..index, insert.iter().cloned());
+                }
+                YamlOutputChange::InsertAfter { after, insert } => {
+                    let index = position(&modules, after)? + 1;
+                    modules.splice(index..index, insert.iter().cloned());
+                }
+            }
+        }
+        Ok(modules)
+    }
+
+    let mut expanded_outputs = IndexMap::with_capacity(outputs.len());
+    let mut insert = |name: String, output: ExpandedOutput| -> eros::Result<()> {
+        if expanded_outputs.contains_key(&name) {
+            bail!("Output '{}' is declared more than once.", name);
+        }
+        expanded_outputs.insert(name, output);
+        Ok(())
+    };
+    for (name, output) in outputs {
+        let YamlOutput::Matrix(matrix_output) = output else {
+            let modules = expand(name, outputs, &mut Vec::new())?;
+            let context = match output {
+                YamlOutput::Extends(extension) => extension.context.clone(),
+                YamlOutput::Context(context_output) => Some(context_output.context.clone()),
+                YamlOutput::Modules(_) | YamlOutput::Matrix(_) => None,
+            };
+            insert(
+                name.clone(),
+                ExpandedOutput {
+                    matrix_values: IndexMap::new(),
+                    context,
+                    modules,
+                },
+            )?;
+            continue;
+        };
+        for matrix_values in matrix_combinations(&matrix_output.matrix)
+            .with_context(|| format!("Invalid matrix for output '{}'.", name))?
+        {
+            let mut context = tera::Context::new();
+            for (key, value) in &matrix_values {
+                context.insert(key.clone(), value);
+            }
+            let matrix_name = Tera::one_off(name, &context, false)
+                .with_context(|| format!("Could not render the output name '{}'.", name))?;
+            let matrix_context = matrix_output
+                .context
+                .as_ref()
+                .map(|output_context| {
+                    Tera::one_off(output_context, &context, false).with_context(|| {
+                        format!(
+                            "Could not render the context '{}' of the output '{}'.",
+                            output_context, name
+                        )
+                    })
+                })
+                .transpose()?;
+            insert(
+                matrix_name,
+                ExpandedOutput {
+                    matrix_values,
+                    context: matrix_context,
+                    modules: matrix_output.modules.clone(),
+                },
+            )?;
+        }
+    }
+    Ok(expanded_outputs)
+}
+
+/// Every combination of matrix values, with the first key changing slowest.
+fn matrix_combinations(
+    matrix: &IndexMap<String, Vec<serde_yaml::Value>>,
+) -> eros::Result<Vec<IndexMap<String, String>>> {
+    let mut combinations = vec![IndexMap::new()];
+    for (key, values) in matrix {
+        if [VARS_TEMPLATE_VAR, ENV_TEMPLATE_VAR, PROFILE_TEMPLATE_VAR].contains(&key.as_str())
+            || !tera_accepts_ident(key)
+        {
+            bail!("Matrix key '{}' is not a valid identifier.", key);
+        }
+        let mut next_combinations = Vec::with_capacity(combinations.len() * values.len());
+        for combination in &combinations {
+            for value in values {
+                let value = match value {
+                    serde_yaml::Value::String(value) => value.clone(),
+                    serde_yaml::Value::Number(value) => value.to_string(),
+                    serde_yaml::Value::Bool(value) => value.to_string(),
+                    _ => bail!(
+                        "Matrix values of '{}' must be strings, numbers or booleans.",
+                        key
+                    ),
+                };
+                let mut combination = combination.clone();
+                combination.insert(key.clone(), value);
+                next_combinations.push(combination);
+            }
+        }
+        combinations = next_combinations;
+    }
+    Ok(combinations)
+}
+
+/// Removes the module entries whose `when:` condition is false from the outputs.
+fn apply_conditions(
+    outputs: IndexMap<String, UseOutput>,
+    vars: &IndexMap<String, String>,
+    profile: Option<&str>,
+) -> eros::Result<(IndexMap<String, UseOutput>, Vec<SkippedModule>)> {
+    let env = env_vars();
+    let mut included_outputs = IndexMap::with_capacity(outputs.len());
+    let mut skipped_modules = Vec::new();
+    for (output_name, output) in outputs {
+        let mut context = template_value_context(vars, &output.matrix_values);
+        context.insert(ENV_TEMPLATE_VAR, &env);
+        context.insert(PROFILE_TEMPLATE_VAR, &profile);
+        let mut modules = Vec::with_capacity(output.modules.len());
+        for module in output.modules {
+            let Some(when) = module.when() else {
+                modules.push(module);
+                continue;
+            };
+            let is_included = evaluate_condition(when, &context).with_context(|| {
+                let entry = match &module {
+                    UseModule::Inline(inline) => format!("inline module `{}`", inline.value),
+                    UseModule::Input(input) => format!("module '{}'", input.name),
+                };
+                format!(
+                    "Could not evaluate `when: {}` of the {} in the output '{}'.",
+                    when, entry, output_name
+                )
+            })?;
+            if is_included {
+                modules.push(module);
+            } else {
+                skipped_modules.push(SkippedModule {
+                    output: output_name.clone(),
+                    module,
+                });
+            }
+        }
+        included_outputs.insert(
+            output_name,
+            UseOutput {
+                matrix_values: output.matrix_values,
+                context: output.context,
+                modules,
+            },
+        );
+    }
+    Ok((included_outputs, skipped_modules))
+}
+
+/// Evaluates the Tera expression of a `when:` condition.
+fn evaluate_condition(when: &str, context: &tera::Context) -> eros::Result<bool> {
+    // The condition is put in an `{% if %}` tag, it may not close it or open other tags
+    if ["{{", "}}", "{%", "%}", "{#", "#}"]
+        .iter()
+        .any(|delimiter| when.contains(delimiter))
+    {
+        bail!(
+            "A condition is a single expression, without `{}`, `{}` or `{}` delimiters.",
+            "{{",
+            "{%",
+            "{#"
+        );
+    }
+    match Tera::one_off(
+        &format!("{{% if {} %}}true{{% endif %}}", when),
+        context,
+        false,
+    ) {
+        Ok(rendered) => Ok(rendered == "true"),
+        // Only the message, the report would show the `{% if %}` tag the condition is put in
+        Err(error) => match error.kind() {
+            tera::ErrorKind::SyntaxError(report) | tera::ErrorKind::RenderingError(report) => {
+                bail!("{}", report.message())
+            }
+            _ => bail!("{}", error),
         },
-        post_build_hook,
-    ))
+    }
+}
+
+/// The module entries excluded from each output by their `when:` condition.
+pub async fn skipped_modules(
+    yard_file_path: &Path,
+    profile: Option<&str>,
+) -> eros::Result<Vec<SkippedModule>> {
+    let validator = yard_validator();
+    let (yard_yaml, profile) =
+        load_yard_file_with_profile(&validator, yard_file_path, profile).await?;
+    let yard_file = to_yard_file(yard_yaml, profile)?;
+    let vars = resolve_vars(yard_file.vars)?;
+    let (_, skipped_modules) = apply_conditions(
+        yard_file.output_container_files,
+        &vars,
+        yard_file.profile.as_deref(),
+    )?;
+    Ok(skipped_modules)
 }
 
 /// resolve and validate fields in the yard.yaml file
@@ -443,14 +1323,54 @@ async fn parse_yard_yaml(path: &Path) -> eros::Result<(YardFile, Option<String>)
 async fn resolve_yard_yaml(
     yard_yaml: YardFile,
     path: &Path,
+    output_dir: &Path,
     do_not_refetch: bool,
-) -> eros::Result<Containerfiles> {
+) -> eros::Result<(Containerfiles, RequiredFiles)> {
     let YardFile {
+        profile,
+        output_dir: _,
+        provenance,
+        vars,
         input_remotes,
         input_modules,
+        mut input_module_roots,
         output_container_files,
+        unselected_outputs: _,
     } = yard_yaml;
     assert!(!output_container_files.is_empty(), "Ouputs should exist");
+    let vars = resolve_vars(vars)?;
+    let (output_container_files, skipped_modules) =
+        apply_conditions(output_container_files, &vars, profile.as_deref())?;
+    // Each build context only receives the required files of the modules of its outputs
+    let mut context_to_module_names: IndexMap<Option<String>, IndexSet<String>> = IndexMap::new();
+    for (output_name, output) in &output_container_files {
+        if let Some(context) = &output.context {
+            is_local_absolute(Path::new(context))
+                .with_context(|| format!("Invalid context for the output '{}'.", output_name))?;
+        }
+        context_to_module_names
+            .entry(output.context.clone())
+            .or_default()
+            .extend(
+                output
+                    .modules
+                    .iter()
+                    .filter_map(UseModule::input_name)
+                    .map(str::to_owned),
+            );
+    }
+    // Modules that are only declared by skipped entries are not fetched
+    let used_module_names: HashSet<&str> = output_container_files
+        .values()
+        .flat_map(|output| output.modules.iter())
+        .filter_map(UseModule::input_name)
+        .collect();
+    let skipped_module_names: HashSet<String> = skipped_modules
+        .iter()
+        .filter_map(|skipped| skipped.module.input_name())
+        .filter(|name| !used_module_names.contains(name))
+        .map(str::to_owned)
+        .collect();
     let mut local_name_to_module_files_data: HashMap<String, ModuleFileData> = HashMap::new();
     let mut module_names_are_unique_check: HashSet<String> = HashSet::new();
     for (name, path) in input_modules {
@@ -458,14 +1378,22 @@ async fn resolve_yard_yaml(
             bail!("A module with name '{}' is declared twice.", name);
         }
         module_names_are_unique_check.insert(name.clone());
-        let module_data = read_module_file(&PathBuf::from(&path)).await?;
+        if skipped_module_names.contains(&name) {
+            continue;
+        }
+        let module_data = read_module(&PathBuf::from(&path)).await?;
         local_name_to_module_files_data.insert(
             name.clone(),
             ModuleFileData {
-                containerfile_data: module_data.containerfile,
+                containerfile_blocks: module_data.containerfile_blocks,
                 config_data: module_data.config,
-                install_stage_data: module_data.install_stage,
-                source_info: SourceInfoKind::Local(LocalModuleInfo { path, name }),
+                config_line: module_data.config_line,
+                config_path: module_data.config_path,
+                source_info: SourceInfoKind::Local(LocalModuleInfo {
+                    root: input_module_roots.remove(&name).unwrap_or_default(),
+                    path,
+                    name,
+                }),
             },
         );
     }
@@ -474,18 +1402,42 @@ async fn resolve_yard_yaml(
             eros::bail!("A module named '{}' is declared more than once", name)
         }
     }
+    let mut input_remotes = input_remotes;
+    for remote in input_remotes.iter_mut() {
+        remote
+            .name_to_path
+            .retain(|name, _| !skipped_module_names.contains(name));
+    }
+    input_remotes.retain(|remote| !remote.name_to_path.is_empty());
 
     let remote_name_to_module_files: HashMap<String, ModuleFileData> =
         retrieve_module_file_data(input_remotes).await?;
     local_name_to_module_files_data.extend(remote_name_to_module_files);
     let name_to_module_files_data = local_name_to_module_files_data;
-    let modules: HashMap<String, ModuleBuilder> =
+    let mut modules: HashMap<String, ModuleBuilder> =
         validate_schema_and_create_module_builders(name_to_module_files_data).await?;
 
     // Resolve
-    resolve_additional_files(&modules, path, do_not_refetch).await?;
+    resolve_template_libraries(&mut modules).await?;
+    let required_files = resolve_additional_files(
+        &modules,
+        &context_to_module_names,
+        path,
+        output_dir,
+        do_not_refetch,
+    )
+    .await?;
+    let mut name_to_context: HashMap<String, String> = HashMap::new();
     let mut containerfiles_to_parts: IndexMap<String, Vec<Module>> = IndexMap::new();
-    for (container_file_name, module_declarations) in output_container_files {
+    for (container_file_name, output) in output_container_files {
+        let UseOutput {
+            matrix_values,
+            context,
+            modules: module_declarations,
+        } = output;
+        if let Some(context) = context {
+            name_to_context.insert(container_file_name.clone(), context);
+        }
         let mut modules_for_container_file: Vec<Module> = Vec::new();
         let mut seen_module_names: HashSet<String> = HashSet::new();
         let mut inline_counter = 0u32;
@@ -494,15 +1446,51 @@ async fn resolve_yard_yaml(
                 UseModule::Inline(inline) => {
                     let synthetic_name = format!("inline_{inline_counter}");
                     inline_counter += 1;
+                    let mut provided_template_values = HashMap::new();
+                    for (var, val) in inline.template_vars {
+                        if [YARD_TEMPLATE_VAR, VARS_TEMPLATE_VAR, ENV_TEMPLATE_VAR]
+                            .contains(&var.as_str())
+                        {
+                            bail!(
+                                "Inline modules may not declare an arg named '{}', it is provided by yard.\n{}",
+                                var,
+                                InlineModuleInfo {
+                                    value: inline.value.clone()
+                                }
+                                .source_location()
+                            );
+                        }
+                        let val = render_with_vars(&val, &vars, &matrix_values)
+                            .and_then(resolve_template_value)
+                            .with_context(|| {
+                                format!(
+                                    "Could not resolve arg '{}' of inline module '{}' in the output '{}'.",
+                                    var, synthetic_name, container_file_name
+                                )
+                            })?;
+                        provided_template_values.insert(var, val);
+                    }
                     modules_for_container_file.push(
                         ModuleBuilder {
-                            containerfile_data: inline.value.clone(),
-                            install_stage_data: None,
-                            independent: false,
+                            stage_data: Vec::new(),
+                            containerfile_data: Some(ContainerfileBlock {
+                                role: None,
+                                span: 0..inline.value.len(),
+                                line: 1,
+                                data: inline.value.clone(),
+                                path: PathBuf::new(),
+                            }),
+                            final_data: Vec::new(),
                             required_files: Vec::new(),
+                            template_paths: Vec::new(),
+                            template_libraries: Vec::new(),
                             required_template_values: HashSet::new(),
-                            optional_template_values: HashSet::new(),
-                            provided_template_values: HashMap::new(),
+                            // Inline modules accept any args
+                            optional_template_values: provided_template_values
+                                .keys()
+                                .cloned()
+                                .collect(),
+                            provided_template_values,
                             source_info: SourceInfoKind::Inline(InlineModuleInfo {
                                 value: inline.value,
                             }),
@@ -529,7 +1517,14 @@ async fn resolve_yard_yaml(
                     let mut module = module.clone();
                     module.name = Some(declared_module.name.clone());
                     for (var, val) in declared_module.template_vars {
-                        let val = resolve_template_value(val)?;
+                        let val = render_with_vars(&val, &vars, &matrix_values)
+                            .and_then(resolve_template_value)
+                            .with_context(|| {
+                                format!(
+                                    "Could not resolve arg '{}' of module '{}' in the output '{}'.",
+                                    var, declared_module.name, container_file_name
+                                )
+                            })?;
                         module.provided_template_values.insert(var, val);
                     }
                     modules_for_container_file.push(module.build()?);
@@ -538,9 +1533,16 @@ async fn resolve_yard_yaml(
         }
         containerfiles_to_parts.insert(container_file_name, modules_for_container_file);
     }
-    Ok(Containerfiles {
-        name_to_module: containerfiles_to_parts,
-    })
+    Ok((
+        Containerfiles {
+            profile,
+            provenance,
+            vars,
+            name_to_module: containerfiles_to_parts,
+            name_to_context,
+        },
+        required_files,
+    ))
 }
 
 #[eros::context("Could not retrieve module file data")]
@@ -559,60 +1561,176 @@ async fn retrieve_module_file_data(
 }
 
 #[eros::context("Could not resolve additional required files")]
+/// Reads the required files of the modules of each build context. Remote files are downloaded,
+/// local files are read unless they are already in place.
 async fn resolve_additional_files(
     name_to_module: &HashMap<String, ModuleBuilder>,
-    local_download_path_root: &Path,
+    context_to_module_names: &IndexMap<Option<String>, IndexSet<String>>,
+    local_path_root: &Path,
+    output_dir: &Path,
     do_not_refetch: bool,
-) -> eros::Result<()> {
-    for (name, module) in name_to_module {
-        match module.source_info {
-            SourceInfoKind::Local(ref local) => {
-                let local_file_path = local_download_path_root.join(&local.path);
-                validate_path_references(&[local_file_path])?;
+) -> eros::Result<RequiredFiles> {
+    for module in name_to_module.values() {
+        if let SourceInfoKind::Local(ref local) = module.source_info {
+            // Paths of modules of included yard files are rebased onto the root, checked from it
+            let module_path = Path::new(&local.path)
+                .strip_prefix(&local.root)
+                .unwrap_or(Path::new(&local.path));
+            validate_path_references(&local_path_root.join(&local.root), &[module_path])?;
+            validate_path_references(&local.root, &module.required_files)
+                .with_context(|| module.source_info.source_location())?;
+        }
+    }
+    let context_to_modules: Vec<(PathBuf, Vec<&ModuleBuilder>)> = context_to_module_names
+        .iter()
+        .map(|(context, module_names)| {
+            let context = PathBuf::from(context.as_deref().unwrap_or_default());
+            let modules = module_names
+                .iter()
+                .filter_map(|name| name_to_module.get(name))
+                .collect();
+            (context, modules)
+        })
+        .collect();
+    for (_, modules) in &context_to_modules {
+        validate_unique_required_files(modules)?;
+    }
+    let mut required_files = Vec::new();
+    for (context, modules) in context_to_modules {
+        for module in modules {
+            match module.source_info {
+                SourceInfoKind::Local(ref local) => {
+                    for file_path in module.required_files.iter() {
+                        let context_file_path = context.join(file_path);
+                        let local_file_path = local.root.join(file_path);
+                        if is_same_file(&local_file_path, &output_dir.join(&context_file_path)) {
+                            continue;
+                        }
+                        let content = fs::read(&local_file_path).await.with_context(|| {
+                            format!(
+                                "Could not read '{}' for\n{}",
+                                local_file_path.display(),
+                                module.source_info.source_location()
+                            )
+                        })?;
+                        required_files.push((context_file_path, content));
+                    }
+                }
+                SourceInfoKind::Remote(ref remote) => {
+                    let git_provider = create_provider(remote.url.clone(), remote.commit.clone())?;
+                    for file_path in module.required_files.iter() {
+                        let context_file_path = context.join(file_path);
+                        let local_download_path = output_dir.join(&context_file_path);
+                        if local_download_path.exists() && do_not_refetch {
+                            println!(
+                                "Note: '{}' is not refetched since it already exists and `--do-not-refetch` is set.",
+                                &local_download_path.display()
+                            );
+                            let content = fs::read(&local_download_path).await?;
+                            required_files.push((context_file_path, content));
+                            continue;
+                        }
+                        let remote_file_path = format!(
+                            "{}/{}",
+                            PathBuf::from(&remote.path).parent().unwrap().display(),
+                            file_path
+                        );
+                        let content = git_provider
+                            .extract_remote_path_data_save_save_to_cache(&remote_file_path)
+                            .await
+                            .with_context(|| {
+                                format!(
+                                    "Could not download '{}' at\n{}",
+                                    &file_path,
+                                    remote.source_location()
+                                )
+                            })?;
+                        required_files.push((context_file_path, content.into_bytes()));
+                    }
+                }
+                SourceInfoKind::Inline(_) => {}
             }
-            SourceInfoKind::Remote(ref remote) => {
-                let git_provider = create_provider(remote.url.clone(), remote.commit.clone())?;
-                for file_path in module.required_files.iter() {
-                    let local_download_path = local_download_path_root.join(file_path);
-                    if local_download_path.exists() && do_not_refetch {
-                        println!(
-                            "Note: '{}' is not refetched since it already exists and `--do-not-refetch` is set.",
-                            &local_download_path.display()
+        }
+    }
+    Ok(required_files)
+}
+
+/// Modules placing their required files in the same build context may not declare the same file.
+fn validate_unique_required_files(modules: &[&ModuleBuilder]) -> eros::Result<()> {
+    for (index, module1) in modules.iter().enumerate() {
+        for module2 in modules.iter().skip(index + 1) {
+            for required_file1 in &module1.required_files {
+                for required_file2 in &module2.required_files {
+                    if required_file1 == required_file2 {
+                        bail!(
+                            "Required file '{}' is declared in both modules:\n{}\n{}\nIf put in the same place one would override the other.",
+                            required_file1,
+                            module1.source_info.source_location(),
+                            module2.source_info.source_location()
                         );
-                        continue;
                     }
-                    let remote_file_path = format!(
-                        "{}/{}",
-                        PathBuf::from(&remote.path).parent().unwrap().display(),
-                        file_path
-                    );
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Whether both paths exist and resolve to the same file.
+fn is_same_file(path1: &Path, path2: &Path) -> bool {
+    match (path1.canonicalize(), path2.canonicalize()) {
+        (Ok(path1), Ok(path2)) => path1 == path2,
+        _ => false,
+    }
+}
+
+#[eros::context("Could not resolve template libraries")]
+async fn resolve_template_libraries(
+    name_to_module: &mut HashMap<String, ModuleBuilder>,
+) -> eros::Result<()> {
+    for module in name_to_module.values_mut() {
+        let mut template_libraries = Vec::new();
+        for template_path in module.template_paths.iter() {
+            let content = match module.source_info {
+                SourceInfoKind::Local(ref local) => {
+                    fs::read_to_string(local.root.join(template_path))
+                        .await
+                        .map_err(|e| eros::error!(e))
+                }
+                SourceInfoKind::Remote(ref remote) => {
+                    let git_provider = create_provider(remote.url.clone(), remote.commit.clone())?;
                     git_provider
-                        .retrieve_file_and_put_at(&remote_file_path, &local_download_path)
+                        .extract_remote_path_data_save_save_to_cache(template_path)
                         .await
-                        .with_context(|| {
-                            format!(
-                                "Could not download '{}' at\n{}",
-                                &file_path,
-                                remote.source_location()
-                            )
-                        })?;
+                }
+                SourceInfoKind::Inline(_) => {
+                    unreachable!("Inline modules do not declare templates")
                 }
             }
-            SourceInfoKind::Inline(_) => {}
+            .with_context(|| {
+                format!(
+                    "Could not read template '{}' for\n{}",
+                    template_path,
+                    module.source_info.source_location()
+                )
+            })?;
+            template_libraries.push((template_path.clone(), content));
         }
+        module.template_libraries = template_libraries;
     }
     Ok(())
 }
 
-fn validate_path_references<T: AsRef<Path>>(files: &[T]) -> eros::Result<()> {
+/// Checks that the files are relative to `root`, without leaving it, and exist.
+fn validate_path_references<T: AsRef<Path>>(root: &Path, files: &[T]) -> eros::Result<()> {
     for file in files {
         let file = file.as_ref();
-        let path = PathBuf::from(file);
-        is_local_absolute(&path)?;
+        is_local_absolute(file)?;
+        let path = root.join(file);
         if !path.exists() {
             bail!(
                 "Path '{}' does not exist, but it should at this point.",
-                file.display()
+                path.display()
             );
         }
     }
@@ -620,7 +1738,7 @@ fn validate_path_references<T: AsRef<Path>>(files: &[T]) -> eros::Result<()> {
 }
 
 /// No "~" or ".."
-fn is_local_absolute(path: &Path) -> eros::Result<()> {
+pub(crate) fn is_local_absolute(path: &Path) -> eros::Result<()> {
     let error = || {
         eros::error!(
             "Path '{}' is not valid. Paths must be relative containing no '~' or '..' components.",
@@ -659,23 +1777,6 @@ async fn validate_schema_and_create_module_builders(
         modules.insert(name, module);
     }
 
-    for (index, (name1, module1)) in modules.iter().enumerate() {
-        for (name1, module2) in modules.iter().skip(index + 1) {
-            for required_file1 in &module1.required_files {
-                for required_file2 in &module2.required_files {
-                    if required_file1 == required_file2 {
-                        bail!(
-                            "Required file '{}' is declared in both modules:\n{}\n{}\nIf put in the same place one would override the other.",
-                            required_file1,
-                            module1.source_info.source_location(),
-                            module2.source_info.source_location()
-                        );
-                    }
-                }
-            }
-        }
-    }
-
     Ok(modules)
 }
 
@@ -684,63 +1785,80 @@ async fn validate_and_create_module_builder<F: Fn(&serde_yaml::Value) -> eros::R
     module_files: ModuleFileData,
     validate_module_schema_fn: F,
 ) -> eros::Result<ModuleBuilder> {
-    let (required_files, required_template_values, optional_template_values, independent) =
-        (|| -> eros::Result<_> {
-            // If there is no config block, default to a non-independent module.
-            let yard_module_yaml: serde_yaml::Value = if module_files.config_data.trim().is_empty()
-            {
-                serde_yaml::Value::Null
-            } else {
-                serde_yaml::from_str(&module_files.config_data)
-                    .with_context(|| "yard-module-schema.json is not valid json.")?
-            };
-
-            validate_module_schema_fn(&yard_module_yaml).context("Schema validation failed.")?;
+    let (
+        required_files,
+        template_paths,
+        required_template_values,
+        optional_template_values,
+        independent,
+    ) = (|| -> eros::Result<_> {
+        // If there is no config block, default to a non-independent module.
+        let yard_module_yaml: serde_yaml::Value = if module_files.config_data.trim().is_empty() {
+            serde_yaml::Value::Null
+        } else {
+            serde_yaml::from_str(&module_files.config_data).with_context(|| {
+                format!(
+                    "{}:{}: The config block is not valid yaml.",
+                    module_files.config_path.display(),
+                    module_files.config_line.unwrap_or(1)
+                )
+            })?
+        };
 
-            let raw_module: YamlModule = serde_yaml::from_value(yard_module_yaml).context(
-                "Was able to serialize yaml, but was unable to convert to internal expected model.",
-            )?;
-            fn tera_accepts_ident(name: &str) -> bool {
-                let template = format!("{{{{ {} }}}}", name);
-                let mut context = tera::Context::new();
-                context.insert(name.to_owned(), "");
-                tera::Tera::one_off(&template, &context, false).is_ok_and(|e| e.is_empty())
-            }
-            let args = raw_module.args.unwrap_or_default();
-            let required_files = raw_module.required_files.unwrap_or_default();
-            let required_template_values: HashSet<String> =
-                args.required.unwrap_or_default().into_iter().collect();
-            let optional_template_values: HashSet<String> =
-                args.optional.unwrap_or_default().into_iter().collect();
-            for template_value in required_template_values
-                .iter()
-                .chain(optional_template_values.iter())
-            {
-                if !tera_accepts_ident(template_value) {
-                    bail!(
-                        "Template variable '{}' is not a valid identifier for a module argument.",
-                        template_value
-                    );
-                }
+        validate_module_schema_fn(&yard_module_yaml).context("Schema validation failed.")?;
+
+        let raw_module: YamlModule = serde_yaml::from_value(yard_module_yaml).context(
+            "Was able to serialize yaml, but was unable to convert to internal expected model.",
+        )?;
+        let args = raw_module.args.unwrap_or_default();
+        let required_files = raw_module.required_files.unwrap_or_default();
+        let required_template_values: HashSet<String> =
+            args.required.unwrap_or_default().into_iter().collect();
+        let optional_template_values: HashSet<String> =
+            args.optional.unwrap_or_default().into_iter().collect();
+        for template_value in required_template_values
+            .iter()
+            .chain(optional_template_values.iter())
+        {
+            if !tera_accepts_ident(template_value) {
+                bail!(
+                    "Template variable '{}' is not a valid identifier for a module argument.",
+                    template_value
+                );
             }
+        }
 
-            for required_file in required_files.iter() {
-                is_local_absolute(&PathBuf::from(required_file))?;
-            }
-            Ok((
-                required_files,
-                required_template_values,
-                optional_template_values,
-                raw_module.independent,
-            ))
-        })()
-        .with_context(|| module_files.source_info.source_location())?;
+        for required_file in required_files.iter() {
+            is_local_absolute(&PathBuf::from(required_file))?;
+        }
+        let template_paths = raw_module.templates.unwrap_or_default();
+        for template_path in template_paths.iter() {
+            is_local_absolute(&PathBuf::from(template_path))?;
+        }
+        Ok((
+            required_files,
+            template_paths,
+            required_template_values,
+            optional_template_values,
+            raw_module.independent,
+        ))
+    })()
+    .with_context(|| module_files.source_info.source_location())?;
+
+    let (stage_data, containerfile_data, final_data) = assign_block_regions(
+        module_files.containerfile_blocks,
+        independent,
+        &module_files.config_path,
+    )
+    .with_context(|| module_files.source_info.source_location())?;
 
     Ok(ModuleBuilder {
-        containerfile_data: module_files.containerfile_data,
-        install_stage_data: module_files.install_stage_data,
-        independent,
+        stage_data,
+        containerfile_data,
+        final_data,
         required_files,
+        template_paths,
+        template_libraries: Vec::new(),
         required_template_values,
         optional_template_values,
         provided_template_values: HashMap::new(),
@@ -749,9 +1867,91 @@ async fn validate_and_create_module_builder<F: Fn(&serde_yaml::Value) -> eros::R
     })
 }
 
+/// Assigns each containerfile/dockerfile block to the region of the output it is placed in:
+/// hoisted stages, the part injected where the module is declared, and finalizers.
+#[allow(clippy::type_complexity)]
+fn assign_block_regions(
+    blocks: Vec<ContainerfileBlock>,
+    independent: bool,
+    config_path: &Path,
+) -> eros::Result<(
+    Vec<ContainerfileBlock>,
+    Option<ContainerfileBlock>,
+    Vec<ContainerfileBlock>,
+)> {
+    let mut stages: Vec<ContainerfileBlock> = Vec::new();
+    let mut install: Option<ContainerfileBlock> = None;
+    let mut finals: Vec<ContainerfileBlock> = Vec::new();
+    if blocks.iter().all(|block| block.role.is_none()) {
+        // No roles, the position of the block determines the region.
+        let mut blocks = blocks.into_iter();
+        if independent {
+            let (Some(build_stage), Some(install_stage)) = (blocks.next(), blocks.next()) else {
+                bail!(
+                    "{}: Module is marked as independent (`independent: true`), but no install stage was found. Independent modules require two containerfile/dockerfile blocks - the first is the build stage and the second is the install stage. Directory modules declare the install stage in `{}`.",
+                    config_path.display(),
+                    MODULE_INSTALL_CONTAINERFILE_NAME
+                );
+            };
+            stages.push(build_stage);
+            install = Some(install_stage);
+        } else {
+            install = blocks.next();
+        }
+        if let Some(block) = blocks.next() {
+            if block.path.ends_with(MODULE_INSTALL_CONTAINERFILE_NAME) {
+                bail!(
+                    "{}: Found an install stage, but the module is not independent. Add `independent: true` to '{}' to build `{}` as a separate stage, or remove '{}'.",
+                    block.location(),
+                    config_path.display(),
+                    MODULE_CONTAINERFILE_NAME,
+                    block.path.display()
+                );
+            }
+            bail!(
+                "{}: Found more containerfile/dockerfile blocks than expected. Use roles to declare additional blocks, e.g. `stage=<name>`, `install` or `final`.",
+                block.location()
+            );
+        }
+        return Ok((stages, install, finals));
+    }
+    for block in blocks {
+        match &block.role {
+            Some(BlockRole::Stage(name)) => {
+                if stages.iter().any(|stage| stage.stage() == Some(name)) {
+                    bail!(
+                        "{}: Stage '{}' is declared more than once.",
+                        block.location(),
+                        name
+                    );
+                }
+                stages.push(block);
+            }
+            // A block without a role alongside blocks with roles is the install part.
+            Some(BlockRole::Install) | None => {
+                if install.is_some() {
+                    bail!(
+                        "{}: Found more than one install block. Blocks without a role are treated as `install` when other blocks declare roles.",
+                        block.location()
+                    );
+                }
+                install = Some(block);
+            }
+            Some(BlockRole::Final) => finals.push(block),
+        }
+    }
+    if independent && stages.is_empty() {
+        bail!(
+            "{}: Module is marked as independent (`independent: true`), but declares no `stage=<name>` blocks.",
+            config_path.display()
+        );
+    }
+    Ok((stages, install, finals))
+}
+
 //************************************************************************//
 
-fn validate_against_schema(
+pub(crate) fn validate_against_schema(
     compiled_schema: &Validator,
     yaml: &serde_yaml::Value,
 ) -> eros::Result<()> {
@@ -782,6 +1982,86 @@ fn validate_against_schema(
 
 //************************************************************************//
 
+fn tera_accepts_ident(name: &str) -> bool {
+    let template = format!("{{{{ {} }}}}", name);
+    let mut context = tera::Context::new();
+    context.insert(name.to_owned(), "");
+    tera::Tera::one_off(&template, &context, false).is_ok_and(|e| e.is_empty())
+}
+
+/// Resolves each var once, in declaration order.
+fn resolve_vars(vars: IndexMap<String, String>) -> eros::Result<IndexMap<String, String>> {
+    let mut resolved_vars = IndexMap::with_capacity(vars.len());
+    for (name, val) in vars {
+        if !tera_accepts_ident(&name) {
+            bail!("Var '{}' is not a valid identifier.", name);
+        }
+        let val = resolve_template_value(val.clone())
+            .with_context(|| format!("Could not resolve var '{}' with value '{}'.", name, val))?;
+        resolved_vars.insert(name, val);
+    }
+    Ok(resolved_vars)
+}
+
+/// Substitutes the `{{ vars.<name> }}` and matrix value `{{ <key> }}` references in a value. Anything
+/// else is kept as is, e.g. the `{{.State.Status}}` of a docker `--format` string.
+fn render_with_vars(
+    val: &str,
+    vars: &IndexMap<String, String>,
+    matrix_values: &IndexMap<String, String>,
+) -> eros::Result<String> {
+    static REFERENCE_REGEX: LazyLock<Regex> = LazyLock::new(|| {
+        Regex::new(r"\{\{\s*(vars\.)?([A-Za-z_][A-Za-z0-9_]*)\s*\}\}").expect("Invalid regex")
+    });
+    let mut rendered = String::with_capacity(val.len());
+    let mut rendered_until = 0;
+    for captures in REFERENCE_REGEX.captures_iter(val) {
+        let name = &captures[2];
+        let value = if captures.get(1).is_some() {
+            vars.get(name).ok_or_else(|| {
+                eros::error!("`{}` references a var that is not declared.", &captures[0])
+            })?
+        } else if let Some(value) = matrix_values.get(name) {
+            value
+        } else {
+            continue;
+        };
+        let reference = captures.get(0).expect("Group 0 is the whole match");
+        rendered.push_str(&val[rendered_until..reference.start()]);
+        rendered.push_str(value);
+        rendered_until = reference.end();
+    }
+    rendered.push_str(&val[rendered_until..]);
+    Ok(rendered)
+}
+
+/// Context for values of the `yard.yaml` file - matrix values and `vars`.
+fn template_value_context(
+    vars: &IndexMap<String, String>,
+    matrix_values: &IndexMap<String, String>,
+) -> tera::Context {
+    let mut context = tera::Context::new();
+    for (key, value) in matrix_values {
+        context.insert(key.clone(), value);
+    }
+    context.insert(VARS_TEMPLATE_VAR, vars);
+    context
+}
+
+/// Environment variables available to templates. Only valid unicode variables are included.
+fn env_vars() -> HashMap<String, String> {
+    std::env::vars_os()
+        .filter_map(|(key, val)| Some((key.into_string().ok()?, val.into_string().ok()?)))
+        .collect()
+}
+
+/// Template variable holding the resolved vars of the `yard.yaml` file.
+const VARS_TEMPLATE_VAR: &str = "vars";
+/// Template variable holding the environment variables, available to inline modules.
+const ENV_TEMPLATE_VAR: &str = "env";
+/// Template variable holding the name of the selected profile, available to `when:` conditions.
+const PROFILE_TEMPLATE_VAR: &str = "profile";
+
 fn resolve_template_value(val: String) -> eros::Result<String> {
     // shell command
     if val.starts_with("$(") && val.ends_with(")") {
@@ -806,33 +2086,155 @@ fn resolve_template_value(val: String) -> eros::Result<String> {
 
 //************************************************************************//
 
-/// Contianfile name and file text
-type Outputs = Vec<(String, String)>;
+/// The rendered Containerfiles, in the order they are declared
+pub type Outputs = Vec<Output>;
+
+/// A rendered Containerfile
+pub struct Output {
+    /// Name of the output in the yard file
+    pub name: String,
+    /// Containerfile path, relative to the output directory
+    pub path: PathBuf,
+    pub content: String,
+    /// Where the lines of the Containerfile come from, in order
+    pub parts: Vec<OutputPart>,
+    /// Module instances of the output, in the order they are declared
+    pub modules: Vec<OutputModule>,
+}
+
+/// A module instance of a rendered Containerfile
+#[derive(Debug, Clone, Serialize)]
+pub struct OutputModule {
+    pub name: String,
+    pub source: SourceInfoKind,
+    /// Resolved args, with secrets redacted
+    pub args: BTreeMap<String, String>,
+    /// Required files, relative to the output directory
+    pub required_files: Vec<PathBuf>,
+    /// Names of the build stages the module declares, e.g. the stages of an independent module
+    pub stages: Vec<String>,
+}
+
+/// The lines of a Containerfile rendered from one template block of a module instance
+#[derive(Debug, Clone, Serialize)]
+pub struct OutputPart {
+    /// First line of the part in the Containerfile, starting at 1. Includes the `####  label  ####`
+    /// separator
+    pub start_line: usize,
+    /// Last line of the part in the Containerfile, inclusive
+    pub end_line: usize,
+    /// Name of the module instance
+    pub module: String,
+    pub source: SourceInfoKind,
+    /// Where the block is placed, e.g. `install stage` or `final`. `None` for a module without stages,
+    /// injected where it is declared
+    pub role: Option<String>,
+    /// Line of the block's first content line in the module file, starting at 1
+    pub template_line: usize,
+    /// First line of the part rendered from the template, after the separator
+    pub content_line: usize,
+    /// Line of the module file each line from `content_line` to `end_line` is rendered from. `None`
+    /// for lines the template generates, e.g. in a `{% for %}` loop
+    pub template_lines: Vec<Option<usize>>,
+    /// Resolved args of the module instance, with secrets redacted
+    pub args: BTreeMap<String, String>,
+}
+
+/// Required file path, relative to the output directory, and file content
+pub type RequiredFiles = Vec<(PathBuf, Vec<u8>)>;
+
+/// Template variable holding the read-only [TemplateYard] object.
+const YARD_TEMPLATE_VAR: &str = "yard";
+/// Template variable holding the unique stage name of an independent module instance.
+const STAGE_TEMPLATE_VAR: &str = "stage";
+/// Template variable mapping each `stage=<name>` of a module to its unique stage name.
+const STAGES_TEMPLATE_VAR: &str = "stages";
+
+/// The unique stage name for a module instance, with an optional stage role suffix. Stage names
+/// must start with a letter and may only contain lowercase alphanumerics, `-`, `_` and `.`.
+fn stage_name(module_name: &str, stage: Option<&str>) -> String {
+    let name = match stage {
+        Some(stage) => format!("yard-{module_name}-{stage}"),
+        None => format!("yard-{module_name}"),
+    };
+    name.to_lowercase()
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.' {
+                c
+            } else {
+                '-'
+            }
+        })
+        .collect()
+}
+
+/// Stage names declared with `FROM <image> AS <name>` in a rendered Containerfile part.
+fn declared_stage_names(containerfile: &str) -> Vec<String> {
+    static FROM_AS_REGEX: LazyLock<Regex> = LazyLock::new(|| {
+        Regex::new(r"(?im)^\s*FROM\s+.*\s+AS\s+(\S+)\s*$").expect("Invalid regex")
+    });
+    FROM_AS_REGEX
+        .captures_iter(containerfile)
+        .map(|captures| captures[1].to_lowercase())
+        .collect()
+}
+
+/// The `yard` object available to every template.
+#[derive(Serialize)]
+struct TemplateYard<'a> {
+    /// Name of the Containerfile being generated.
+    output: &'a str,
+    /// This module instance.
+    module: TemplateModule<'a>,
+    /// All module instances in the output, in the order they are declared.
+    modules: &'a [TemplateModule<'a>],
+}
+
+#[derive(Serialize)]
+struct TemplateModule<'a> {
+    name: &'a str,
+    source: &'a SourceInfoKind,
+}
 
 /// Apply args to each template and collect
-fn apply_templating(yard: Containerfiles, with_cache_busting: bool) -> eros::Result<Outputs> {
+fn apply_templating(
+    yard: Containerfiles,
+    path: &Path,
+    required_files: &RequiredFiles,
+    with_cache_busting: bool,
+) -> eros::Result<Outputs> {
+    /// The required files of a build context, relative to the context.
+    fn context_files(required_files: &RequiredFiles, context: &Path) -> HashMap<PathBuf, Vec<u8>> {
+        required_files
+            .iter()
+            .filter_map(|(file_path, content)| {
+                let file_path = file_path.strip_prefix(context).ok()?;
+                Some((file_path.to_path_buf(), content.clone()))
+            })
+            .collect()
+    }
+
     let mut tera = Tera::default();
     // No escaping, shouldn't matter though since we don't use these file types, but just to future proof.
     tera.autoescape_on(Vec::<&str>::new());
     tera.set_escape_fn(|e, writer| writer.write(e.as_bytes()).map(|_| ()));
+    template_functions::register(&mut tera, context_files(required_files, Path::new("")));
 
-    /// Renders a single template with the provided values, attaching source info on error.
+    /// Renders a single template with the provided context, attaching source info on error.
     fn render(
         tera: &Tera,
-        template: &str,
-        provided_template_values: &HashMap<String, String>,
+        template: &ContainerfileBlock,
+        context: &tera::Context,
         source_info: &SourceInfoKind,
     ) -> eros::Result<String> {
-        let mut context = tera::Context::new();
-        for (var, val) in provided_template_values {
-            context.insert(var.clone(), val);
-        }
-        let rendered = tera.render_str(template, &context, false);
+        let rendered = tera.render_str(&template.data, context, false);
         let rendered = match rendered {
             Ok(val) => val,
             Err(e) => Err(e).with_context(|| {
                 format!(
-                    "Could not render template for Containerfile part found at:\n{}",
+                    "Could not render template for Containerfile part starting at line {} found at:\n{}",
+                    template.line,
                     source_info.source_location(),
                 )
             })?,
@@ -840,184 +2242,307 @@ fn apply_templating(yard: Containerfiles, with_cache_busting: bool) -> eros::Res
         Ok(rendered.trim().to_string())
     }
 
+    let env = env_vars();
     let mut outputs = Vec::new();
     for (containerfile_name, included_modules) in yard.name_to_module {
-        // Build stages of independent modules are hoisted to the start of the Containerfile.
-        let mut build_stage_parts: Vec<String> = Vec::new();
+        // Required files of the output are resolved against its build context
+        let output_tera = yard
+            .name_to_context
+            .get(&containerfile_name)
+            .map(|context| {
+                let mut output_tera = tera.clone();
+                template_functions::register(
+                    &mut output_tera,
+                    context_files(required_files, Path::new(context)),
+                );
+                output_tera
+            });
+        let tera = output_tera.as_ref().unwrap_or(&tera);
+        // Stages are hoisted to the start of the Containerfile and finalizers appended to the end.
+        let mut stage_parts: Vec<(String, OutputPart)> = Vec::new();
         let mut container_file_resolved_parts = Vec::new();
-        for included_module in included_modules {
+        let mut final_parts: Vec<(String, OutputPart)> = Vec::new();
+        let module_args: Vec<BTreeMap<String, String>> = included_modules
+            .iter()
+            .map(|module| provenance::redacted_args(&module.provided_template_values))
+            .collect();
+        let output_context = yard.name_to_context.get(&containerfile_name).map(Path::new);
+        let mut output_modules: Vec<OutputModule> = Vec::new();
+        // Stage names declared by hoisted stages, to detect collisions between modules.
+        let mut stage_name_to_source: HashMap<String, SourceInfoKind> = HashMap::new();
+        let template_modules: Vec<TemplateModule> = included_modules
+            .iter()
+            .map(|module| TemplateModule {
+                name: module
+                    .name
+                    .as_deref()
+                    .expect("Should be provided at this point"),
+                source: &module.source_info,
+            })
+            .collect();
+        for (included_module, args) in included_modules.iter().zip(&module_args) {
             let label = included_module.source_info.label();
-            if included_module.independent {
-                // Hoist the build stage to the start.
-                let mut build_stage = render(
-                    &tera,
-                    &included_module.containerfile_template,
-                    &included_module.provided_template_values,
-                    &included_module.source_info,
-                )?;
-                if with_cache_busting {
-                    let name = included_module
-                        .name
-                        .as_deref()
-                        .expect("Should be provided at this point");
-                    build_stage = apply_cache_busting(&build_stage, name);
-                }
-                let part = format!("####  {label} (build stage)  ####\n\n{build_stage}\n");
-                build_stage_parts.push(part);
-                // Inject the install stage where the module is declared.
-                let install_template = included_module
-                    .install_stage_template
-                    .as_ref()
-                    .expect("Independent modules must have an install stage; this is checked in ModuleBuilder::build");
-                let mut install_stage = render(
-                    &tera,
-                    install_template,
-                    &included_module.provided_template_values,
-                    &included_module.source_info,
-                )?;
-                if with_cache_busting {
-                    let name = included_module
-                        .name
-                        .as_deref()
-                        .expect("Should be provided at this point");
-                    install_stage = apply_cache_busting(&install_stage, name);
-                }
-                let part = format!("####  {label} (install stage)  ####\n\n{install_stage}\n");
-                container_file_resolved_parts.push(part);
+            let name = included_module
+                .name
+                .as_deref()
+                .expect("Should be provided at this point");
+            let mut context = tera::Context::new();
+            for (var, val) in &included_module.provided_template_values {
+                context.insert(var.clone(), val);
+            }
+            if let SourceInfoKind::Inline(_) = included_module.source_info {
+                context.insert(VARS_TEMPLATE_VAR, &yard.vars);
+                context.insert(ENV_TEMPLATE_VAR, &env);
+            }
+            context.insert(
+                YARD_TEMPLATE_VAR,
+                &TemplateYard {
+                    output: &containerfile_name,
+                    module: TemplateModule {
+                        name,
+                        source: &included_module.source_info,
+                    },
+                    modules: &template_modules,
+                },
+            );
+            if !included_module.stage_templates.is_empty() {
+                context.insert(STAGE_TEMPLATE_VAR, &stage_name(name, None));
+                let stages: HashMap<&str, String> = included_module
+                    .stage_templates
+                    .iter()
+                    .filter_map(|stage| stage.stage())
+                    .map(|stage| (stage, stage_name(name, Some(stage))))
+                    .collect();
+                context.insert(STAGES_TEMPLATE_VAR, &stages);
+            }
+            // Template libraries are only visible to the module that declares them.
+            let module_tera = if included_module.template_libraries.is_empty() {
+                None
             } else {
-                let mut rendered_part = render(
-                    &tera,
-                    &included_module.containerfile_template,
-                    &included_module.provided_template_values,
+                let mut module_tera = tera.clone();
+                module_tera
+                    .add_raw_templates(included_module.template_libraries.clone())
+                    .with_context(|| {
+                        format!(
+                            "Could not load the templates declared by:\n{}",
+                            included_module.source_info.source_location()
+                        )
+                    })?;
+                Some(module_tera)
+            };
+            // Line ranges are filled in once the parts are in place
+            let output_part =
+                |role: Option<String>, template: &ContainerfileBlock, rendered: &str| OutputPart {
+                    start_line: 0,
+                    end_line: 0,
+                    module: name.to_owned(),
+                    source: included_module.source_info.clone(),
+                    role,
+                    template_line: template.line,
+                    content_line: 0,
+                    template_lines: template_lines(template, rendered, with_cache_busting),
+                    args: args.clone(),
+                };
+            let render_part = |template: &ContainerfileBlock| -> eros::Result<String> {
+                let rendered = render(
+                    module_tera.as_ref().unwrap_or(tera),
+                    template,
+                    &context,
                     &included_module.source_info,
                 )?;
-                if with_cache_busting {
-                    let module_name = included_module
-                        .name
-                        .as_deref()
-                        .expect("Should be provided at this point");
-                    rendered_part = apply_cache_busting(&rendered_part, module_name);
+                Ok(if with_cache_busting {
+                    apply_cache_busting(&rendered, name)
+                } else {
+                    rendered
+                })
+            };
+            let mut stages = Vec::new();
+            for stage in &included_module.stage_templates {
+                let rendered_stage = render_part(stage)?;
+                for declared_stage in declared_stage_names(&rendered_stage) {
+                    if let Some(other_source) = stage_name_to_source.get(&declared_stage) {
+                        bail!(
+                            "Build stage name '{}' is declared more than once in the output '{}'. Consider using `{{{{ {} }}}}` or `{{{{ {}.<name> }}}}` for the stage name. Declared in:\n{}\n{}",
+                            declared_stage,
+                            containerfile_name,
+                            STAGE_TEMPLATE_VAR,
+                            STAGES_TEMPLATE_VAR,
+                            other_source.source_location(),
+                            included_module.source_info.source_location()
+                        );
+                    }
+                    stage_name_to_source
+                        .insert(declared_stage.clone(), included_module.source_info.clone());
+                    stages.push(declared_stage);
                 }
-                let part = format!("####  {label}  ####\n\n{rendered_part}\n");
-                container_file_resolved_parts.push(part);
+                let role = match stage.stage() {
+                    Some(stage_name) => format!("{stage_name} stage"),
+                    None => "build stage".to_owned(),
+                };
+                let part = format!("####  {label} ({role})  ####\n\n{rendered_stage}\n");
+                let output_part = output_part(Some(role), stage, &rendered_stage);
+                stage_parts.push((part, output_part));
+            }
+            if let Some(template) = &included_module.containerfile_template {
+                let rendered_part = render_part(template)?;
+                let (part, role) = if included_module.stage_templates.is_empty() {
+                    (format!("####  {label}  ####\n\n{rendered_part}\n"), None)
+                } else {
+                    (
+                        format!("####  {label} (install stage)  ####\n\n{rendered_part}\n"),
+                        Some("install stage".to_owned()),
+                    )
+                };
+                container_file_resolved_parts
+                    .push((part, output_part(role, template, &rendered_part)));
+            }
+            for template in &included_module.final_templates {
+                let rendered_part = render_part(template)?;
+                final_parts.push((
+                    format!("####  {label} (final)  ####\n\n{rendered_part}\n"),
+                    output_part(Some("final".to_owned()), template, &rendered_part),
+                ));
             }
+            output_modules.push(OutputModule {
+                name: name.to_owned(),
+                source: included_module.source_info.clone(),
+                args: args.clone(),
+                required_files: included_module
+                    .required_files
+                    .iter()
+                    .map(|file_path| output_context.unwrap_or(Path::new("")).join(file_path))
+                    .collect(),
+                stages,
+            });
         }
-        let mut all_parts = build_stage_parts;
+        let mut all_parts = stage_parts;
         all_parts.extend(container_file_resolved_parts);
-        outputs.push((containerfile_name, all_parts.join("\n")));
+        all_parts.extend(final_parts);
+        let provenance_modules: Vec<ProvenanceModule> = template_modules
+            .iter()
+            .zip(&module_args)
+            .map(|(module, args)| ProvenanceModule {
+                name: module.name,
+                source: module.source,
+                args,
+            })
+            .collect();
+        let mut containerfile = String::new();
+        if yard.provenance.header == Some(true) {
+            containerfile.push_str(&provenance::header(&provenance_modules));
+        }
+        if let Some(profile) = &yard.profile {
+            containerfile.push_str(&format!("# Profile: {profile}\n\n"));
+        }
+        // Parts are separated by a blank line
+        let mut line = containerfile.lines().count() + 1;
+        let mut parts = Vec::new();
+        for (index, (part, mut output_part)) in all_parts.into_iter().enumerate() {
+            if index > 0 {
+                containerfile.push('\n');
+            }
+            containerfile.push_str(&part);
+            output_part.start_line = line;
+            output_part.end_line = line + part.lines().count() - 1;
+            // After the separator, the blank line following it and the cache busting `ARG`
+            output_part.content_line = line + 2 + usize::from(with_cache_busting);
+            line = output_part.end_line + 2;
+            parts.push(output_part);
+        }
+        // Appended last, so the labels are in the final stage
+        if yard.provenance.labels == Some(true) {
+            let labels = provenance::labels(
+                &containerfile_name,
+                yard.profile.as_deref(),
+                &provenance_modules,
+            );
+            containerfile.push_str(&format!("\n####  ~LABELS~  ####\n\n{labels}\n"));
+        }
+        let containerfile_path = match output_context {
+            Some(context) => context.join(&containerfile_name),
+            None => PathBuf::from(&containerfile_name),
+        };
+        outputs.push(Output {
+            name: containerfile_name,
+            path: containerfile_path,
+            content: containerfile,
+            parts,
+            modules: output_modules,
+        });
     }
     Ok(outputs)
 }
 
-//************************************************************************//
-
-#[derive(PartialEq)]
-enum CapturingState {
-    None,
-    Containerfile,
-    Config,
-}
-
-pub struct ModuleData {
-    pub containerfile: String,
-    pub config: String,
-    /// Install stage for independent modules. `None` for non-independent modules or when only one
-    /// containerfile/dockerfile block is present.
-    pub install_stage: Option<String>,
-}
-
-#[eros::context("Could not read '{}' as a module.", &PathBuf::from(&path).display())]
-pub async fn read_module_file(path: &Path) -> eros::Result<ModuleData> {
-    let data = fs::read_to_string(path).await?;
-    // Collect containerfile/dockerfile blocks in the order they appear. Independent modules use
-    // two blocks: the first is the build stage, the second is the install stage.
-    let mut container_data: Vec<String> = Vec::new();
-    let mut config_data = None;
-    let mut capture_status = CapturingState::None;
-    let mut capture = String::new();
-    for line in data.lines() {
-        let compare_line = line.trim().to_lowercase();
-        if compare_line == "```yaml" {
-            if config_data.is_some() {
-                continue;
-            }
-            if capture_status != CapturingState::None {
-                eros::bail!("Found another config start line before finishing the previous one");
-            }
-            capture_status = CapturingState::Config;
-            continue;
-        } else if compare_line == "```containerfile" || compare_line == "```dockerfile" {
-            if capture_status != CapturingState::None {
-                eros::bail!(
-                    "Found another Containerfile start line before finishing the previous one"
-                );
+/// The line of the module file each line of `rendered` comes from, aligned with a line diff.
+/// `None` for lines the template generates.
+fn template_lines(
+    template: &ContainerfileBlock,
+    rendered: &str,
+    with_cache_busting: bool,
+) -> Vec<Option<usize>> {
+    // The cache busting `ARG` is the first line, it is not from the template
+    let rendered = if with_cache_busting {
+        rendered.split_once('\n').map_or("", |(_, rest)| rest)
+    } else {
+        rendered
+    };
+    let template_data: Vec<&str> = template.data.lines().collect();
+    let rendered: Vec<&str> = rendered.lines().collect();
+    let mut template_lines = Vec::with_capacity(rendered.len());
+    for op in TextDiff::from_slices(&template_data, &rendered).ops() {
+        match *op {
+            DiffOp::Equal { old_index, len, .. } => {
+                template_lines
+                    .extend((old_index..old_index + len).map(|i| Some(template.line + i)));
             }
-            capture_status = CapturingState::Containerfile;
-            continue;
-        } else if compare_line == "```" {
-            match capture_status {
-                CapturingState::None => {
-                    // Could be another documentation block ignore
-                }
-                CapturingState::Containerfile => {
-                    container_data.push(capture.clone());
-                    capture.clear();
-                    capture_status = CapturingState::None;
-                }
-                CapturingState::Config => {
-                    config_data = Some(capture.clone());
-                    capture.clear();
-                    capture_status = CapturingState::None;
+            DiffOp::Replace {
+                old_index,
+                old_len,
+                new_index,
+                new_len,
+            } => {
+                // Lines changed in place, e.g. by `{{ arg }}`, match their template line in order.
+                // A line repeated by a loop matches the last matched template line again
+                let regexes: Vec<Regex> = template_data[old_index..old_index + old_len]
+                    .iter()
+                    .map(|template_line| template_line_regex(template_line))
+                    .collect();
+                let mut next_old_index = old_index;
+                let mut last_matched = None;
+                for rendered_line in &rendered[new_index..new_index + new_len] {
+                    let matches = |i: &usize| regexes[*i - old_index].is_match(rendered_line);
+                    let matched = (next_old_index..old_index + old_len)
+                        .find(matches)
+                        .or_else(|| last_matched.filter(matches));
+                    if let Some(matched) = matched {
+                        next_old_index = matched + 1;
+                        last_matched = Some(matched);
+                    }
+                    template_lines.push(matched.map(|i| template.line + i));
                 }
             }
-            continue;
-        }
-        if capture_status != CapturingState::None {
-            capture.push_str(line);
-            capture.push('\n');
-        }
-    }
-    Ok(match (container_data.is_empty(), config_data) {
-        (true, None) => {
-            // No sections found for either so interpret the entire file as a containerfile
-            ModuleData {
-                containerfile: data,
-                config: String::new(),
-                install_stage: None,
-            }
-        }
-        (true, Some(_)) => {
-            eros::bail!("Found config in the module file, but no containerfile data")
-        }
-        (false, None) => {
-            // No config block. If there are two containerfile blocks, treat the second as the
-            // install stage (independent module without explicit config).
-            let install_stage = if container_data.len() > 1 {
-                Some(container_data.remove(1))
-            } else {
-                None
-            };
-            ModuleData {
-                containerfile: container_data.remove(0),
-                config: String::new(),
-                install_stage,
-            }
-        }
-        (false, Some(config_data)) => {
-            let install_stage = if container_data.len() > 1 {
-                Some(container_data.remove(1))
-            } else {
-                None
-            };
-            ModuleData {
-                containerfile: container_data.remove(0),
-                config: config_data,
-                install_stage,
+            DiffOp::Insert { new_len, .. } => {
+                template_lines.extend(std::iter::repeat_n(None, new_len));
             }
+            DiffOp::Delete { .. } => {}
         }
-    })
+    }
+    template_lines
 }
 
+/// Matches the lines a template line renders to, with its `{{ }}` expressions as wildcards.
+fn template_line_regex(template_line: &str) -> Regex {
+    static EXPRESSION_REGEX: LazyLock<Regex> =
+        LazyLock::new(|| Regex::new(r"\{\{.*?\}\}").expect("Invalid regex"));
+    let literals: Vec<String> = EXPRESSION_REGEX
+        .split(template_line)
+        .map(regex::escape)
+        .collect();
+    Regex::new(&format!("^{}$", literals.join(".*"))).expect("Escaped literals are a valid regex")
+}
+
+//************************************************************************//
+
 fn apply_cache_busting(containerfile: &str, module_name: &str) -> String {
     let module_name = module_name.replace("-", "_").to_uppercase();
     format!("ARG CACHE_BUST_{module_name}=1\n{containerfile}")
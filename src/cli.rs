@@ -2,6 +2,8 @@ use std::path::PathBuf;
 
 use clap::{Parser, Subcommand};
 
+use crate::manifest::ManifestFormat;
+
 #[derive(Parser)]
 #[clap(name = "yard", author = "Henry McMahon", version = "0.3.13", about = "A declarative reusable decentralized approach for defining containers", long_about = None)]
 pub struct Cli {
@@ -16,6 +18,15 @@ pub enum Commands {
         /// Path to the `yard.yaml` file.
         #[clap(default_value = ".")]
         path: PathBuf,
+        /// Treat `path` as a workspace root containing a `yard-workspace.yaml`, and run for every member.
+        #[clap(long, default_value = "false")]
+        workspace: bool,
+        /// Path to the yard file to use instead of the `yard.yaml` file in `path`.
+        #[clap(short, long, conflicts_with = "workspace")]
+        file: Option<PathBuf>,
+        /// Directory to write the Containerfiles and required files to. Overrides `output_dir` of the yard file.
+        #[clap(long, conflicts_with = "workspace")]
+        out_dir: Option<PathBuf>,
         /// If set, any required files for modules that already exist on the local path will not be refetched.
         /// This may make building faster. And is also useful for testing - if you want to make sure a local file does not
         /// get overriden.
@@ -24,12 +35,87 @@ pub enum Commands {
         /// If set, cache busting `ARG` statements are injected before each module. This allows busting build cache for specific modules.
         #[clap(long, default_value = "false")]
         with_cache_busting: bool,
+        /// If set, nothing is written. Instead a unified diff is printed for each generated file that
+        /// differs from the file on disk, and the command fails if any file is out of date. The
+        /// pre-build hook is not run.
+        #[clap(long, default_value = "false")]
+        check: bool,
+        /// Also write a `yard-manifest.json` to the output directory, describing the outputs, their
+        /// modules, args, required files and stages.
+        #[clap(long, value_name = "FORMAT")]
+        emit_manifest: Option<ManifestFormat>,
+        /// Profile of the `yard.yaml` file to use.
+        #[clap(long, env = "YARD_PROFILE")]
+        profile: Option<String>,
     },
     /// Print output filenames in the order they are declared in `yard.yaml`.
     Outputs {
         /// Path to the `yard.yaml` file.
         #[clap(default_value = ".")]
         path: PathBuf,
+        /// Treat `path` as a workspace root containing a `yard-workspace.yaml`, and run for every member.
+        #[clap(long, default_value = "false")]
+        workspace: bool,
+        /// Path to the yard file to use instead of the `yard.yaml` file in `path`.
+        #[clap(short, long, conflicts_with = "workspace")]
+        file: Option<PathBuf>,
+        /// Profile of the `yard.yaml` file to use.
+        #[clap(long, env = "YARD_PROFILE")]
+        profile: Option<String>,
+    },
+    /// Print what `build` would change on disk, without writing anything.
+    Diff {
+        /// Path to the `yard.yaml` file.
+        #[clap(default_value = ".")]
+        path: PathBuf,
+        /// Path to the yard file to use instead of the `yard.yaml` file in `path`.
+        #[clap(short, long)]
+        file: Option<PathBuf>,
+        /// Directory the Containerfiles and required files are written to. Overrides `output_dir` of the yard file.
+        #[clap(long)]
+        out_dir: Option<PathBuf>,
+        /// Render with cache busting `ARG` statements, like `build --with-cache-busting`.
+        #[clap(long, default_value = "false")]
+        with_cache_busting: bool,
+        /// Include the manifest, like `build --emit-manifest`.
+        #[clap(long, value_name = "FORMAT")]
+        emit_manifest: Option<ManifestFormat>,
+        /// Profile of the `yard.yaml` file to use.
+        #[clap(long, env = "YARD_PROFILE")]
+        profile: Option<String>,
+    },
+    /// Explain which module each line of an output is rendered from. Without an output, explain the
+    /// modules skipped in each output by their `when:` condition.
+    Explain {
+        /// Path to the `yard.yaml` file.
+        #[clap(default_value = ".")]
+        path: PathBuf,
+        /// Output to explain, by name or path relative to the output directory.
+        output: Option<String>,
+        /// Line of the output to trace back to its module. Without a line, print the layout of the output.
+        #[clap(requires = "output")]
+        line: Option<usize>,
+        /// Path to the yard file to use instead of the `yard.yaml` file in `path`.
+        #[clap(short, long)]
+        file: Option<PathBuf>,
+        /// Render with cache busting `ARG` statements, like `build --with-cache-busting`. Needs an output.
+        #[clap(long, default_value = "false", requires = "output")]
+        with_cache_busting: bool,
+        /// Profile of the `yard.yaml` file to use.
+        #[clap(long, env = "YARD_PROFILE")]
+        profile: Option<String>,
+    },
+    /// Remove the files written by the last `build`: the Containerfiles and the required files.
+    Clean {
+        /// Path to the `yard.yaml` file.
+        #[clap(default_value = ".")]
+        path: PathBuf,
+        /// Path to the yard file to use instead of the `yard.yaml` file in `path`.
+        #[clap(short, long)]
+        file: Option<PathBuf>,
+        /// Directory the Containerfiles and required files were written to. Overrides `output_dir` of the yard file.
+        #[clap(long)]
+        out_dir: Option<PathBuf>,
     },
     /// Initialize a `yard.yaml` file.
     Init {
@@ -42,5 +128,11 @@ pub enum Commands {
         /// Path to the `yard.yaml` file.
         #[clap(default_value = ".")]
         path: PathBuf,
+        /// Treat `path` as a workspace root containing a `yard-workspace.yaml`, and run for every member.
+        #[clap(long, default_value = "false")]
+        workspace: bool,
+        /// Path to the yard file to use instead of the `yard.yaml` file in `path`.
+        #[clap(short, long, conflicts_with = "workspace")]
+        file: Option<PathBuf>,
     },
 }
@@ -0,0 +1,226 @@
+use std::collections::HashSet;
+use std::io::IsTerminal;
+use std::path::{Path, PathBuf};
+
+use eros::bail;
+use similar::TextDiff;
+
+use crate::build::RenderedBuild;
+use crate::generated_files;
+
+/// A rendered file that differs from the file on disk.
+struct FileChange<'a> {
+    /// Path of the file on disk
+    file_path: PathBuf,
+    /// Content on disk, `None` if the file does not exist
+    on_disk: Option<Vec<u8>>,
+    rendered: &'a [u8],
+}
+
+impl FileChange<'_> {
+    /// Unified diff from the file on disk to the rendered file.
+    fn unified_diff(&self) -> String {
+        let (Ok(old), Ok(new)) = (
+            str::from_utf8(self.on_disk.as_deref().unwrap_or_default()),
+            str::from_utf8(self.rendered),
+        ) else {
+            return format!("Binary file '{}' differs\n", self.file_path.display());
+        };
+        let old_header = match self.on_disk {
+            Some(_) => format!("a/{}", self.file_path.display()),
+            None => "/dev/null".to_owned(),
+        };
+        TextDiff::from_lines(old, new)
+            .unified_diff()
+            .header(&old_header, &format!("b/{}", self.file_path.display()))
+            .to_string()
+    }
+}
+
+/// Compares the rendered files with the files on disk, printing a unified
+/// diff for each file that is out of date. Writes nothing.
+pub fn check(rendered: &RenderedBuild) -> eros::Result<()> {
+    let changes = file_changes(rendered);
+    for change in &changes {
+        print!("{}", change.unified_diff());
+    }
+    if !changes.is_empty() {
+        bail!(
+            "{} of {} generated files are out of date.",
+            changes.len(),
+            rendered.files().len()
+        );
+    }
+    println!("All generated files are up to date.");
+    Ok(())
+}
+
+/// Prints what a build would change on disk: a unified diff for each file that would be created
+/// or modified, and the generated files that are no longer produced. Writes nothing.
+pub async fn diff(rendered: &RenderedBuild) -> eros::Result<()> {
+    let color = std::io::stdout().is_terminal() && std::env::var_os("NO_COLOR").is_none();
+    let changes = file_changes(rendered);
+    let mut created = 0;
+    for change in &changes {
+        if change.on_disk.is_none() {
+            created += 1;
+            println!("New file '{}'", change.file_path.display());
+        }
+        let unified_diff = change.unified_diff();
+        if color {
+            print!("{}", colorize(&unified_diff));
+        } else {
+            print!("{unified_diff}");
+        }
+    }
+    let previously_generated =
+        generated_files::read(&rendered.output_dir, &rendered.generated_files_name).await?;
+    let generated_by_others =
+        generated_files::read_others(&rendered.output_dir, &rendered.generated_files_name).await?;
+    let no_longer_produced =
+        no_longer_produced(rendered, &previously_generated, &generated_by_others);
+    for file_path in &no_longer_produced {
+        println!(
+            "Not produced anymore '{}', it exists on disk but is no longer declared.",
+            file_path.display()
+        );
+    }
+    if changes.is_empty() && no_longer_produced.is_empty() {
+        println!("No changes.");
+    } else {
+        println!(
+            "{} new, {} modified, {} no longer produced.",
+            created,
+            changes.len() - created,
+            no_longer_produced.len()
+        );
+    }
+    Ok(())
+}
+
+/// The rendered files that differ from the files on disk.
+fn file_changes(rendered: &RenderedBuild) -> Vec<FileChange<'_>> {
+    rendered
+        .files()
+        .into_iter()
+        .filter_map(|(file_path, content)| {
+            let file_path = display_path(&rendered.output_dir.join(file_path));
+            let on_disk = std::fs::read(&file_path).ok();
+            if on_disk.as_deref() == Some(content) {
+                return None;
+            }
+            Some(FileChange {
+                file_path,
+                on_disk,
+                rendered: content,
+            })
+        })
+        .collect()
+}
+
+/// Files on disk generated by yard that the build does not produce anymore, e.g. since their output
+/// or module was removed. These are the files of the previous build of the yard file that are not
+/// produced, and the Containerfiles generated by yard in the output directory and build contexts
+/// that are not produced. Files other yard files generate and the outputs the profile leaves out
+/// are not flagged, like `yard build` keeps them.
+fn no_longer_produced(
+    rendered: &RenderedBuild,
+    previously_generated: &[PathBuf],
+    generated_by_others: &[PathBuf],
+) -> Vec<PathBuf> {
+    let in_output_dir = |file_path: &PathBuf| display_path(&rendered.output_dir.join(file_path));
+    let output_paths: Vec<PathBuf> = rendered
+        .outputs
+        .iter()
+        .map(|output| in_output_dir(&output.path))
+        .collect();
+    // Produced by this build, generated by another yard file, or an output the profile leaves out
+    let mut accounted_for: HashSet<PathBuf> = rendered
+        .files()
+        .into_iter()
+        .map(|(file_path, _)| display_path(&rendered.output_dir.join(file_path)))
+        .collect();
+    accounted_for.extend(generated_by_others.iter().map(in_output_dir));
+    accounted_for.extend(rendered.unselected_outputs.iter().map(in_output_dir));
+    let mut no_longer_produced: Vec<PathBuf> = previously_generated
+        .iter()
+        .map(in_output_dir)
+        .filter(|file_path| {
+            rendered.unselected_outputs.is_empty()
+                && !accounted_for.contains(file_path)
+                && file_path.is_file()
+        })
+        .collect();
+    let mut dirs: Vec<PathBuf> = Vec::new();
+    for file_path in &output_paths {
+        let dir = file_path.parent().unwrap_or(Path::new("")).to_path_buf();
+        if !dirs.contains(&dir) {
+            dirs.push(dir);
+        }
+    }
+    for dir in dirs {
+        let read_dir = if dir.as_os_str().is_empty() {
+            std::fs::read_dir(".")
+        } else {
+            std::fs::read_dir(&dir)
+        };
+        let Ok(entries) = read_dir else {
+            continue;
+        };
+        let mut file_paths: Vec<PathBuf> = entries
+            .filter_map(|entry| Some(dir.join(entry.ok()?.file_name())))
+            .filter(|file_path| {
+                file_path.is_file()
+                    && !accounted_for.contains(file_path)
+                    && !no_longer_produced.contains(file_path)
+                    && file_path
+                        .file_name()
+                        .is_some_and(|name| name.to_string_lossy().contains("Containerfile"))
+                    && is_generated_containerfile(file_path)
+            })
+            .collect();
+        file_paths.sort();
+        no_longer_produced.extend(file_paths);
+    }
+    no_longer_produced
+}
+
+/// Whether the file contains the `####  <module>  ####` separators yard puts before each module.
+fn is_generated_containerfile(file_path: &Path) -> bool {
+    let Ok(content) = std::fs::read_to_string(file_path) else {
+        return false;
+    };
+    content
+        .lines()
+        .any(|line| line.starts_with("####  ") && line.ends_with("  ####"))
+}
+
+/// The path without the leading `./` of the default output directory.
+fn display_path(file_path: &Path) -> PathBuf {
+    file_path
+        .strip_prefix(".")
+        .unwrap_or(file_path)
+        .to_path_buf()
+}
+
+/// Colors the lines of a unified diff with ANSI escape codes.
+fn colorize(unified_diff: &str) -> String {
+    const RESET: &str = "\x1b[0m";
+    unified_diff
+        .lines()
+        .map(|line| {
+            let color = if line.starts_with("+++") || line.starts_with("---") {
+                "\x1b[1m"
+            } else if line.starts_with('+') {
+                "\x1b[32m"
+            } else if line.starts_with('-') {
+                "\x1b[31m"
+            } else if line.starts_with("@@") {
+                "\x1b[36m"
+            } else {
+                return format!("{line}\n");
+            };
+            format!("{color}{line}{RESET}\n")
+        })
+        .collect()
+}
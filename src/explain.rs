@@ -0,0 +1,109 @@
+use eros::bail;
+
+use crate::build::{Output, OutputPart, RenderedBuild};
+use crate::provenance::describe_source;
+
+/// Prints the module instance, source, args and template line `line` of the output is rendered
+/// from. Without a line, prints the line ranges of every module instance in the output.
+pub fn explain(
+    rendered: &RenderedBuild,
+    output_name: &str,
+    line: Option<usize>,
+) -> eros::Result<()> {
+    let Some(output) = rendered
+        .outputs
+        .iter()
+        .find(|output| output.name == output_name || output.path.as_os_str() == output_name)
+    else {
+        bail!(
+            "No output named '{}'. The outputs are '{}'.",
+            output_name,
+            rendered
+                .outputs
+                .iter()
+                .map(|output| output.name.as_str())
+                .collect::<Vec<_>>()
+                .join("', '")
+        );
+    };
+    match line {
+        Some(line) => explain_line(output, line),
+        None => {
+            explain_layout(output);
+            Ok(())
+        }
+    }
+}
+
+fn explain_layout(output: &Output) {
+    println!(
+        "'{}' has {} lines:",
+        output.name,
+        output.content.lines().count()
+    );
+    let width = output.content.lines().count().to_string().len();
+    for part in &output.parts {
+        println!(
+            "  {:>width$}-{:<width$}  {}  {}",
+            part.start_line,
+            part.end_line,
+            module_role(part),
+            describe_source(&part.source)
+        );
+    }
+}
+
+fn explain_line(output: &Output, line: usize) -> eros::Result<()> {
+    let line_count = output.content.lines().count();
+    if line == 0 || line > line_count {
+        bail!(
+            "Line {} is out of range, '{}' has {} lines.",
+            line,
+            output.name,
+            line_count
+        );
+    }
+    let Some(part) = output
+        .parts
+        .iter()
+        .find(|part| part.start_line <= line && line <= part.end_line)
+    else {
+        println!(
+            "Line {} of '{}' is not rendered from a module.",
+            line, output.name
+        );
+        return Ok(());
+    };
+    println!("Line {} of '{}':", line, output.name);
+    println!("  Module: {}", module_role(part));
+    println!("  Source: {}", describe_source(&part.source));
+    println!("  Lines: {}-{}", part.start_line, part.end_line);
+    let template_line = match line.checked_sub(part.content_line) {
+        None => format!("{}, the start of the template block", part.template_line),
+        Some(index) => match part.template_lines.get(index).copied().flatten() {
+            Some(template_line) => template_line.to_string(),
+            None => format!(
+                "unknown, generated by the template block starting at line {}",
+                part.template_line
+            ),
+        },
+    };
+    println!("  Template line: {template_line}");
+    if part.args.is_empty() {
+        println!("  Args: none");
+    } else {
+        println!("  Args:");
+        for (arg, value) in &part.args {
+            println!("    {arg}={value}");
+        }
+    }
+    Ok(())
+}
+
+/// The module instance name, with the stage the part is placed in.
+fn module_role(part: &OutputPart) -> String {
+    match &part.role {
+        Some(role) => format!("{} ({})", part.module, role),
+        None => part.module.clone(),
+    }
+}
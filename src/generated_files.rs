@@ -0,0 +1,119 @@
+use std::path::{Path, PathBuf};
+
+use eros::Context;
+use tokio::fs;
+
+use crate::build::{YARD_YAML_FILE_NAME, is_local_absolute};
+
+/// Name of the manifest listing the files written by the last build of `yard.yaml`, relative to the
+/// output directory it is in. Other yard files have their own, see [file_name].
+pub const GENERATED_FILES_FILE_NAME: &str = ".yard-generated";
+
+/// Name of the manifest of the yard file, so yard files sharing an output directory do not remove
+/// each other's files. `.yard-generated.<stem>` for yard files other than `yard.yaml`.
+pub fn file_name(yard_file_path: &Path) -> String {
+    match yard_file_path.file_name() {
+        Some(name) if name == YARD_YAML_FILE_NAME => GENERATED_FILES_FILE_NAME.to_owned(),
+        _ => format!(
+            "{}.{}",
+            GENERATED_FILES_FILE_NAME,
+            yard_file_path
+                .file_stem()
+                .unwrap_or_default()
+                .to_string_lossy()
+        ),
+    }
+}
+
+const HEADER: &str = "# Files generated by yard, relative to this directory. Used by `yard clean` and to remove\n# files that are no longer generated.\n";
+
+/// The files listed in the manifest `file_name` of `output_dir`. Empty if there is no manifest.
+pub async fn read(output_dir: &Path, file_name: &str) -> eros::Result<Vec<PathBuf>> {
+    let manifest_path = output_dir.join(file_name);
+    if !fs::try_exists(&manifest_path).await? {
+        return Ok(Vec::new());
+    }
+    let manifest = fs::read_to_string(&manifest_path)
+        .await
+        .with_context(|| format!("Could not read '{}'.", manifest_path.display()))?;
+    let mut file_paths = Vec::new();
+    for line in manifest.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let file_path = PathBuf::from(line);
+        is_local_absolute(&file_path)
+            .with_context(|| format!("Invalid entry in '{}'.", manifest_path.display()))?;
+        file_paths.push(file_path);
+    }
+    Ok(file_paths)
+}
+
+/// The files listed in the manifests of `output_dir` other than `file_name`, written by the other
+/// yard files that share the output directory.
+pub async fn read_others(output_dir: &Path, file_name: &str) -> eros::Result<Vec<PathBuf>> {
+    let mut file_paths = Vec::new();
+    let Ok(mut entries) = fs::read_dir(output_dir).await else {
+        return Ok(file_paths);
+    };
+    while let Some(entry) = entries.next_entry().await? {
+        let entry_name = entry.file_name().to_string_lossy().into_owned();
+        if entry_name != file_name
+            && (entry_name == GENERATED_FILES_FILE_NAME
+                || entry_name.starts_with(&format!("{GENERATED_FILES_FILE_NAME}.")))
+        {
+            file_paths.extend(read(output_dir, &entry_name).await?);
+        }
+    }
+    Ok(file_paths)
+}
+
+/// The manifest listing `file_paths`.
+pub fn render(file_paths: &[&Path]) -> String {
+    let mut manifest = HEADER.to_owned();
+    for file_path in file_paths {
+        manifest.push_str(&format!("{}\n", file_path.display()));
+    }
+    manifest
+}
+
+/// Removes the files listed in the manifest `file_name` of `output_dir`, the directories left empty
+/// by them, and the manifest itself. Files also listed by the manifests of other yard files are
+/// kept.
+pub async fn clean(output_dir: &Path, file_name: &str) -> eros::Result<()> {
+    let manifest_path = output_dir.join(file_name);
+    if !fs::try_exists(&manifest_path).await? {
+        println!(
+            "Nothing to clean, no '{}' found in '{}'.",
+            file_name,
+            output_dir.display()
+        );
+        return Ok(());
+    }
+    let generated_by_others = read_others(output_dir, file_name).await?;
+    for file_path in read(output_dir, file_name).await? {
+        let target_path = output_dir.join(&file_path);
+        if generated_by_others.contains(&file_path) || !fs::try_exists(&target_path).await? {
+            continue;
+        }
+        fs::remove_file(&target_path)
+            .await
+            .with_context(|| format!("Could not remove '{}'.", target_path.display()))?;
+        println!("Removed '{}'", target_path.display());
+        remove_empty_parents(output_dir, &file_path).await;
+    }
+    fs::remove_file(&manifest_path)
+        .await
+        .with_context(|| format!("Could not remove '{}'.", manifest_path.display()))?;
+    Ok(())
+}
+
+/// Removes the directories between `output_dir` and the removed `file_path` that are empty.
+pub async fn remove_empty_parents(output_dir: &Path, file_path: &Path) {
+    for dir in file_path.ancestors().skip(1) {
+        if dir.as_os_str().is_empty() || fs::remove_dir(output_dir.join(dir)).await.is_err() {
+            break;
+        }
+    }
+}
@@ -0,0 +1,372 @@
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    path::{Component, Path, PathBuf},
+};
+
+use eros::{Context, bail};
+use serde::Deserialize;
+use serde_yaml::{Mapping, Value};
+use tokio::fs;
+
+use crate::build::is_local_absolute;
+use crate::remote_resolvers::{GitProvider, create_provider};
+
+/// Key of the yard file listing the yard files to include.
+const INCLUDE_KEY: &str = "include";
+
+/// Paths, as keys, of the mappings that are merged entry by entry. Values at any other path are
+/// replaced as a whole.
+const MERGED_PATHS: [&[&str]; 8] = [
+    &[],
+    &["inputs"],
+    &["inputs", "modules"],
+    &["vars"],
+    &["outputs"],
+    &["profiles"],
+    &["hooks"],
+    &["hooks", "build"],
+];
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(untagged)]
+enum YamlInclude {
+    /// Path relative to the directory of the including yard file
+    Local(String),
+    Remote(YamlRemoteInclude),
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct YamlRemoteInclude {
+    url: String,
+    commit: String,
+    /// Path relative to the root of the repo
+    path: String,
+}
+
+/// Where an included yard file comes from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum IncludeSource {
+    Local(PathBuf),
+    Remote {
+        url: String,
+        commit: String,
+        path: String,
+    },
+}
+
+impl Display for IncludeSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            IncludeSource::Local(path) => write!(f, "'{}'", path.display()),
+            IncludeSource::Remote { url, commit, path } => {
+                write!(f, "'{path}' of '{url}' at commit '{commit}'")
+            }
+        }
+    }
+}
+
+impl IncludeSource {
+    /// The source of `path`, included by this yard file. The path is relative to the directory of
+    /// this yard file, in its repo for a remote yard file.
+    fn include(&self, path: &str) -> eros::Result<IncludeSource> {
+        match self {
+            IncludeSource::Local(including_path) => Ok(IncludeSource::Local(normalize(
+                &parent(including_path).join(path),
+            ))),
+            IncludeSource::Remote {
+                url,
+                commit,
+                path: including_path,
+            } => {
+                let path = normalize(&parent(Path::new(including_path)).join(path));
+                is_local_absolute(&path).with_context(|| format!("It is included by {}.", self))?;
+                Ok(IncludeSource::Remote {
+                    url: url.clone(),
+                    commit: commit.clone(),
+                    path: path.to_string_lossy().into_owned(),
+                })
+            }
+        }
+    }
+}
+
+/// Replaces the `include:` of a yard file with the merged content of the included yard files.
+/// Includes are merged in order, and conflicting values between includes are errors. The including
+/// file takes precedence over its includes.
+///
+/// Also returns the directory of the included yard file each of its local modules is declared in,
+/// by module name. Module paths are rebased onto it, and the module's required files and templates
+/// are relative to it.
+pub async fn resolve_includes(
+    yard_yaml: Value,
+    yard_file_path: &Path,
+) -> eros::Result<(Value, HashMap<String, PathBuf>)> {
+    let mut module_roots = HashMap::new();
+    let yard_yaml = resolve(
+        yard_yaml,
+        IncludeSource::Local(normalize(yard_file_path)),
+        &mut Vec::new(),
+        &mut module_roots,
+    )
+    .await?;
+    Ok((yard_yaml, module_roots))
+}
+
+async fn resolve(
+    yard_yaml: Value,
+    source: IncludeSource,
+    including: &mut Vec<IncludeSource>,
+    module_roots: &mut HashMap<String, PathBuf>,
+) -> eros::Result<Value> {
+    // Anything but a mapping is reported by schema validation
+    let Value::Mapping(mut yard_yaml) = yard_yaml else {
+        return Ok(yard_yaml);
+    };
+    let Some(includes) = yard_yaml.remove(INCLUDE_KEY) else {
+        return Ok(Value::Mapping(yard_yaml));
+    };
+    let includes: Vec<YamlInclude> = serde_yaml::from_value(includes).with_context(|| {
+        format!(
+            "`{}` of {} should be a list of local paths or remote references with `url`, `commit` and `path`.",
+            INCLUDE_KEY, source
+        )
+    })?;
+    including.push(source);
+    let mut merged = Mapping::new();
+    for include in includes {
+        let include_source = match include {
+            YamlInclude::Local(path) => including.last().expect("Pushed above").include(&path)?,
+            YamlInclude::Remote(remote) => IncludeSource::Remote {
+                url: remote.url,
+                commit: remote.commit,
+                path: remote.path,
+            },
+        };
+        if including.contains(&include_source) {
+            let cycle: Vec<String> = including
+                .iter()
+                .chain([&include_source])
+                .map(ToString::to_string)
+                .collect();
+            bail!(
+                "Yard files include each other in a cycle: {}.",
+                cycle.join(" -> ")
+            );
+        }
+        let Value::Mapping(mut included) = read_include(&include_source).await? else {
+            bail!("Included {} is not a yaml mapping.", include_source);
+        };
+        let local_modules = rebase_local_modules(&mut included, &include_source)
+            .with_context(|| format!("Could not include {}.", include_source))?;
+        let included = Box::pin(resolve(
+            Value::Mapping(included),
+            include_source.clone(),
+            including,
+            module_roots,
+        ))
+        .await?;
+        let Value::Mapping(included) = included else {
+            unreachable!("Resolving the includes of a mapping gives a mapping");
+        };
+        if let IncludeSource::Local(path) = &include_source {
+            for name in local_modules {
+                module_roots.insert(name, parent(path).to_path_buf());
+            }
+        }
+        merge(&mut merged, included, &[], false)
+            .with_context(|| format!("Could not include {}.", include_source))?;
+    }
+    let source = including.pop().expect("Pushed above");
+    // Local modules declared by this file are relative to its directory, not the one of an include
+    for name in declared_modules(&yard_yaml).0 {
+        if let Some(name) = name.as_str() {
+            module_roots.remove(name);
+        }
+    }
+    remove_overridden_modules(&mut merged, &yard_yaml);
+    merge(&mut merged, yard_yaml, &[], true)
+        .with_context(|| format!("Could not merge the includes of {}.", source))?;
+    Ok(Value::Mapping(merged))
+}
+
+/// Rebases the local module paths of an included yard file onto its directory. The local modules of
+/// a remote yard file become modules of its remote, at the same commit. Returns the names of the
+/// local modules left.
+fn rebase_local_modules(
+    yard_yaml: &mut Mapping,
+    source: &IncludeSource,
+) -> eros::Result<Vec<String>> {
+    let Some(Value::Mapping(inputs)) = yard_yaml.get_mut("inputs") else {
+        return Ok(Vec::new());
+    };
+    let Some(Value::Mapping(modules)) = inputs.get_mut("modules") else {
+        return Ok(Vec::new());
+    };
+    let include_dir = match source {
+        IncludeSource::Local(path) => parent(path),
+        IncludeSource::Remote { path, .. } => parent(Path::new(path)),
+    };
+    let mut names = Vec::new();
+    for (name, path) in modules.iter_mut() {
+        // Anything else is reported by schema validation
+        let (Some(name), Value::String(path)) = (name.as_str(), path) else {
+            continue;
+        };
+        is_local_absolute(Path::new(path))
+            .with_context(|| format!("Invalid path of the module '{}'.", name))?;
+        *path = include_dir.join(&path).to_string_lossy().into_owned();
+        names.push(name.to_owned());
+    }
+    let IncludeSource::Remote { url, commit, .. } = source else {
+        return Ok(names);
+    };
+    let modules = inputs.remove("modules").expect("Checked above");
+    let remote = Value::Mapping(Mapping::from_iter([
+        (Value::from("url"), Value::from(url.as_str())),
+        (Value::from("commit"), Value::from(commit.as_str())),
+        (Value::from("modules"), modules),
+    ]));
+    match inputs.get_mut("remotes") {
+        Some(Value::Sequence(remotes)) => remotes.push(remote),
+        _ => {
+            inputs.insert(Value::from("remotes"), Value::Sequence(vec![remote]));
+        }
+    }
+    Ok(Vec::new())
+}
+
+/// The directory of `path`, empty for the current directory.
+fn parent(path: &Path) -> &Path {
+    path.parent().unwrap_or(Path::new(""))
+}
+
+/// Removes the `.` and `..` components of `path` without touching the file system, so a yard file
+/// is named the same way by all of its includers.
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir
+                if matches!(
+                    normalized.components().next_back(),
+                    Some(Component::Normal(_))
+                ) =>
+            {
+                normalized.pop();
+            }
+            component => normalized.push(component),
+        }
+    }
+    normalized
+}
+
+async fn read_include(source: &IncludeSource) -> eros::Result<Value> {
+    let data = match source {
+        IncludeSource::Local(path) => fs::read_to_string(path).await.map_err(|e| eros::error!(e)),
+        IncludeSource::Remote { url, commit, path } => {
+            is_local_absolute(&PathBuf::from(path))?;
+            let git_provider = create_provider(url.clone(), commit.clone())?;
+            git_provider
+                .extract_remote_path_data_save_save_to_cache(path)
+                .await
+        }
+    }
+    .with_context(|| format!("Could not read included {}.", source))?;
+    let included = serde_yaml::from_str(&data)
+        .with_context(|| format!("Included {} is not valid yaml.", source))?;
+    Ok(included)
+}
+
+/// Merges `overlay` into `base`. Conflicting values are replaced if `overrides`, otherwise they are
+/// an error. Remotes are concatenated.
+fn merge(base: &mut Mapping, overlay: Mapping, path: &[&str], overrides: bool) -> eros::Result<()> {
+    for (key, value) in overlay {
+        let key_name = key.as_str().unwrap_or_default().to_owned();
+        let key_path: Vec<&str> = path.iter().copied().chain([key_name.as_str()]).collect();
+        if key_path == ["inputs", "remotes"]
+            && let (Some(Value::Sequence(base_remotes)), Value::Sequence(remotes)) =
+                (base.get_mut(&key), &value)
+        {
+            for remote in remotes {
+                if !base_remotes.contains(remote) {
+                    base_remotes.push(remote.clone());
+                }
+            }
+            continue;
+        }
+        let Some(base_value) = base.get_mut(&key) else {
+            base.insert(key, value);
+            continue;
+        };
+        if *base_value == value {
+            continue;
+        }
+        match (base_value, value) {
+            (Value::Mapping(base_value), Value::Mapping(value))
+                if MERGED_PATHS.contains(&key_path.as_slice()) =>
+            {
+                merge(base_value, value, &key_path, overrides)?;
+            }
+            (base_value, value) if overrides => *base_value = value,
+            _ => bail!(
+                "'{}' is declared with different values by more than one include.",
+                key_path.join(".")
+            ),
+        }
+    }
+    Ok(())
+}
+
+/// Removes the modules the including file declares from the inputs of its includes, so the
+/// including file's declarations take precedence. Local modules declared by both are instead
+/// overridden by merging.
+fn remove_overridden_modules(merged: &mut Mapping, yard_yaml: &Mapping) {
+    let (local_modules, remote_modules) = declared_modules(yard_yaml);
+    let Some(Value::Mapping(inputs)) = merged.get_mut("inputs") else {
+        return;
+    };
+    if let Some(Value::Mapping(modules)) = inputs.get_mut("modules") {
+        modules.retain(|name, _| !remote_modules.contains(name));
+    }
+    if let Some(Value::Sequence(remotes)) = inputs.get_mut("remotes") {
+        for remote in remotes.iter_mut() {
+            if let Some(Value::Mapping(modules)) = remote.get_mut("modules") {
+                modules.retain(|name, _| {
+                    !local_modules.contains(name) && !remote_modules.contains(name)
+                });
+            }
+        }
+        remotes.retain(|remote| {
+            remote
+                .get("modules")
+                .and_then(Value::as_mapping)
+                .is_none_or(|modules| !modules.is_empty())
+        });
+    }
+}
+
+/// Names of the local and remote modules declared in `inputs`.
+fn declared_modules(yard_yaml: &Mapping) -> (Vec<Value>, Vec<Value>) {
+    let mut local_modules = Vec::new();
+    let mut remote_modules = Vec::new();
+    let Some(inputs) = yard_yaml.get("inputs") else {
+        return (local_modules, remote_modules);
+    };
+    if let Some(modules) = inputs.get("modules").and_then(Value::as_mapping) {
+        local_modules.extend(modules.keys().cloned());
+    }
+    for remote in inputs
+        .get("remotes")
+        .and_then(Value::as_sequence)
+        .into_iter()
+        .flatten()
+    {
+        if let Some(modules) = remote.get("modules").and_then(Value::as_mapping) {
+            remote_modules.extend(modules.keys().cloned());
+        }
+    }
+    (local_modules, remote_modules)
+}
@@ -4,19 +4,32 @@
 mod build;
 mod cli;
 mod common;
+mod diff;
+mod explain;
+mod generated_files;
+mod include;
+mod manifest;
+mod provenance;
 mod remote_resolvers;
+mod staging;
+mod template_functions;
 mod init;
+mod module_file;
 mod update;
+mod workspace;
 
 use std::process::exit;
 
-use build::{build, output_order};
+use build::{
+    BuildOptions, build, output_order, render, resolve_output_dir, skipped_modules, yard_file_path,
+};
 use clap::Parser;
 use cli::{Cli, Commands};
 use init::init;
 use tracing::Level;
 use tracing_subscriber::FmtSubscriber;
 use update::update;
+use workspace::{build_workspace, update_workspace, workspace_output_order};
 
 #[tokio::main]
 async fn main() {
@@ -36,17 +49,142 @@ async fn main() {
         match cli.command {
             Commands::Build {
                 path,
+                workspace: true,
+                file: _,
+                out_dir: _,
                 do_not_refetch,
                 with_cache_busting,
-            } => build(&path, do_not_refetch, with_cache_busting).await,
-            Commands::Outputs { path } => {
-                for output_name in output_order(&path).await? {
+                check,
+                emit_manifest,
+                profile,
+            } => {
+                build_workspace(
+                    &path,
+                    do_not_refetch,
+                    with_cache_busting,
+                    check,
+                    emit_manifest,
+                    profile.as_deref(),
+                )
+                .await
+            }
+            Commands::Build {
+                path,
+                workspace: false,
+                file,
+                out_dir,
+                do_not_refetch,
+                with_cache_busting,
+                check,
+                emit_manifest,
+                profile,
+            } => {
+                let options = BuildOptions {
+                    yard_file: file.as_deref(),
+                    out_dir: out_dir.as_deref(),
+                    do_not_refetch,
+                    with_cache_busting,
+                    profile: profile.as_deref(),
+                    emit_manifest,
+                    // `--check` writes nothing, the hook may
+                    run_pre_build_hook: !check,
+                };
+                build(&path, &options, check).await
+            }
+            Commands::Outputs {
+                path,
+                workspace,
+                file,
+                profile,
+            } => {
+                let output_order = if workspace {
+                    workspace_output_order(&path, profile.as_deref()).await?
+                } else {
+                    let yard_file_path = yard_file_path(&path, file.as_deref());
+                    output_order(&yard_file_path, profile.as_deref()).await?
+                };
+                for output_name in output_order {
                     println!("{output_name}");
                 }
                 Ok(())
             }
+            Commands::Diff {
+                path,
+                file,
+                out_dir,
+                with_cache_busting,
+                emit_manifest,
+                profile,
+            } => {
+                let options = BuildOptions {
+                    yard_file: file.as_deref(),
+                    out_dir: out_dir.as_deref(),
+                    do_not_refetch: false,
+                    with_cache_busting,
+                    profile: profile.as_deref(),
+                    emit_manifest,
+                    run_pre_build_hook: false,
+                };
+                diff::diff(&render(&path, &options).await?).await
+            }
+            Commands::Explain {
+                output: Some(output),
+                line,
+                path,
+                file,
+                with_cache_busting,
+                profile,
+            } => {
+                let options = BuildOptions {
+                    yard_file: file.as_deref(),
+                    out_dir: None,
+                    do_not_refetch: false,
+                    with_cache_busting,
+                    profile: profile.as_deref(),
+                    emit_manifest: None,
+                    run_pre_build_hook: false,
+                };
+                explain::explain(&render(&path, &options).await?, &output, line)
+            }
+            Commands::Explain {
+                output: None,
+                line: _,
+                path,
+                file,
+                with_cache_busting: _,
+                profile,
+            } => {
+                let yard_file_path = yard_file_path(&path, file.as_deref());
+                let skipped_modules = skipped_modules(&yard_file_path, profile.as_deref()).await?;
+                if skipped_modules.is_empty() {
+                    println!("No modules are skipped.");
+                }
+                for skipped_module in skipped_modules {
+                    println!("{skipped_module}");
+                }
+                Ok(())
+            }
+            Commands::Clean {
+                path,
+                file,
+                out_dir,
+            } => {
+                let output_dir =
+                    resolve_output_dir(&path, file.as_deref(), out_dir.as_deref()).await?;
+                let file_name = generated_files::file_name(&yard_file_path(&path, file.as_deref()));
+                generated_files::clean(&output_dir, &file_name).await
+            }
             Commands::Init { path } => init(&path).await,
-            Commands::Update { path } => update(&path),
+            Commands::Update {
+                path,
+                workspace: true,
+                file: _,
+            } => update_workspace(&path).await,
+            Commands::Update {
+                path,
+                workspace: false,
+                file,
+            } => update(&yard_file_path(&path, file.as_deref())),
         }
     }
     .await;
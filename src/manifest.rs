@@ -0,0 +1,111 @@
+use std::path::Path;
+
+use clap::ValueEnum;
+use eros::Context;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use tokio::fs;
+
+use crate::build::{OutputModule, Outputs, RequiredFiles};
+
+/// Version of the manifest format, increased on breaking changes. The format is described by
+/// `src/schemas/yard-manifest-schema.json`.
+const MANIFEST_VERSION: u32 = 1;
+
+/// Name of the manifest, relative to the output directory.
+pub const MANIFEST_FILE_NAME: &str = "yard-manifest.json";
+
+/// Formats `build --emit-manifest` can write.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ManifestFormat {
+    Json,
+}
+
+#[derive(Serialize)]
+struct Manifest<'a> {
+    version: u32,
+    /// Version of yard that wrote the manifest
+    yard_version: &'a str,
+    /// Name of the selected profile
+    profile: Option<&'a str>,
+    /// In the order they are declared
+    outputs: Vec<ManifestOutput<'a>>,
+    /// Required files of all outputs, in the order they are first required
+    required_files: Vec<ManifestRequiredFile<'a>>,
+}
+
+#[derive(Serialize)]
+struct ManifestOutput<'a> {
+    name: &'a str,
+    /// Relative to the output directory
+    path: &'a Path,
+    modules: &'a [OutputModule],
+}
+
+#[derive(Serialize)]
+struct ManifestRequiredFile<'a> {
+    /// Relative to the output directory
+    path: &'a Path,
+    /// Hex encoded sha256 of the content
+    sha256: String,
+}
+
+/// The manifest describing what went into each output. Required files that are not written by the
+/// build, since they are already in place, are hashed from the output directory.
+pub async fn render(
+    outputs: &Outputs,
+    required_files: &RequiredFiles,
+    output_dir: &Path,
+    profile: Option<&str>,
+) -> eros::Result<String> {
+    let mut manifest_required_files: Vec<ManifestRequiredFile> = Vec::new();
+    let required_file_paths = outputs
+        .iter()
+        .flat_map(|output| &output.modules)
+        .flat_map(|module| &module.required_files);
+    for file_path in required_file_paths {
+        if manifest_required_files
+            .iter()
+            .any(|required_file| required_file.path == file_path)
+        {
+            continue;
+        }
+        let digest = match required_files
+            .iter()
+            .find(|(required_file_path, _)| required_file_path == file_path)
+        {
+            Some((_, content)) => Sha256::digest(content),
+            None => {
+                let on_disk_path = output_dir.join(file_path);
+                let content = fs::read(&on_disk_path).await.with_context(|| {
+                    format!(
+                        "Could not read '{}' for the manifest.",
+                        on_disk_path.display()
+                    )
+                })?;
+                Sha256::digest(content)
+            }
+        };
+        manifest_required_files.push(ManifestRequiredFile {
+            path: file_path,
+            sha256: format!("{digest:x}"),
+        });
+    }
+    let manifest = Manifest {
+        version: MANIFEST_VERSION,
+        yard_version: env!("CARGO_PKG_VERSION"),
+        profile,
+        outputs: outputs
+            .iter()
+            .map(|output| ManifestOutput {
+                name: &output.name,
+                path: &output.path,
+                modules: &output.modules,
+            })
+            .collect(),
+        required_files: manifest_required_files,
+    };
+    let mut content = serde_json::to_string_pretty(&manifest).expect("Manifests are serializable");
+    content.push('\n');
+    Ok(content)
+}
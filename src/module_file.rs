@@ -0,0 +1,354 @@
+use std::{
+    fmt::Display,
+    ops::Range,
+    path::{Path, PathBuf},
+};
+
+use eros::{Context, bail};
+use pulldown_cmark::{CodeBlockKind, Event, Parser, Tag, TagEnd};
+use tokio::fs;
+
+/// Role of a containerfile/dockerfile block, declared in the block's info string. e.g.
+/// "```dockerfile stage=compile".
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BlockRole {
+    /// `stage=<name>`. Hoisted to the start of the Containerfile.
+    Stage(String),
+    /// `install`. Injected where the module is declared.
+    Install,
+    /// `final`. Appended to the end of the Containerfile.
+    Final,
+}
+
+impl BlockRole {
+    /// Parses the attributes following the language of a block's info string. Returns the role and
+    /// the other attributes, e.g. `title="Base"`, which are ignored.
+    fn from_attributes<'a>(
+        attributes: impl Iterator<Item = &'a str>,
+    ) -> eros::Result<(Option<BlockRole>, Vec<&'a str>)> {
+        let mut role = None;
+        let mut ignored = Vec::new();
+        for attribute in attributes {
+            let attribute_role = match attribute {
+                "install" => BlockRole::Install,
+                "final" => BlockRole::Final,
+                _ => match attribute.strip_prefix("stage=") {
+                    Some("") => bail!("Block role `stage=` is missing the name of the stage."),
+                    Some(name) => BlockRole::Stage(name.to_owned()),
+                    None => {
+                        ignored.push(attribute);
+                        continue;
+                    }
+                },
+            };
+            if let Some(role) = &role {
+                bail!(
+                    "Block role '{}' is declared after block role '{}'. A block has at most one role.",
+                    attribute_role,
+                    role
+                );
+            }
+            role = Some(attribute_role);
+        }
+        Ok((role, ignored))
+    }
+}
+
+impl Display for BlockRole {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BlockRole::Stage(name) => write!(f, "stage={name}"),
+            BlockRole::Install => write!(f, "install"),
+            BlockRole::Final => write!(f, "final"),
+        }
+    }
+}
+
+/// A containerfile/dockerfile block of a module file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ContainerfileBlock {
+    /// `None` if the block does not declare a role.
+    pub role: Option<BlockRole>,
+    pub data: String,
+    /// Byte offsets of the block's content in the module file.
+    pub span: Range<usize>,
+    /// Line of the block's first content line in the module file, starting at 1.
+    pub line: usize,
+    /// File the block is read from. Empty for inline modules.
+    pub path: PathBuf,
+}
+
+impl ContainerfileBlock {
+    /// The name of the stage for `stage=<name>` blocks.
+    pub fn stage(&self) -> Option<&str> {
+        match &self.role {
+            Some(BlockRole::Stage(name)) => Some(name),
+            _ => None,
+        }
+    }
+
+    /// `file:line` of the block's first content line.
+    pub fn location(&self) -> String {
+        format!("{}:{}", self.path.display(), self.line)
+    }
+}
+
+pub struct ModuleData {
+    /// Containerfile/dockerfile blocks in the order they appear.
+    pub containerfile_blocks: Vec<ContainerfileBlock>,
+    pub config: String,
+    /// Line of the config block's first content line in the module file, starting at 1. `None` if
+    /// there is no config block.
+    pub config_line: Option<usize>,
+    /// File the config is read from, the `module.yaml` of a directory module.
+    pub config_path: PathBuf,
+}
+
+/// Config file of a directory module.
+pub const MODULE_CONFIG_FILE_NAME: &str = "module.yaml";
+/// Containerfile of a directory module. The build stage for independent modules.
+pub const MODULE_CONTAINERFILE_NAME: &str = "Containerfile";
+/// Install stage of an independent directory module.
+pub const MODULE_INSTALL_CONTAINERFILE_NAME: &str = "install.Containerfile";
+/// All files that make up a directory module, excluding required files.
+pub const MODULE_DIRECTORY_FILE_NAMES: [&str; 3] = [
+    MODULE_CONFIG_FILE_NAME,
+    MODULE_CONTAINERFILE_NAME,
+    MODULE_INSTALL_CONTAINERFILE_NAME,
+];
+
+/// Reads a module in either format - a single module file, or a module directory.
+pub async fn read_module(path: &Path) -> eros::Result<ModuleData> {
+    if path.is_dir() {
+        read_module_dir(path).await
+    } else {
+        read_module_file(path).await
+    }
+}
+
+#[eros::context("Could not read '{}' as a module.", &PathBuf::from(&path).display())]
+pub async fn read_module_file(path: &Path) -> eros::Result<ModuleData> {
+    let data = fs::read_to_string(path).await?;
+    parse_module_file(path, data)
+}
+
+/// Reads a module directory containing a `module.yaml`, a `Containerfile` and optionally an
+/// `install.Containerfile`.
+#[eros::context("Could not read '{}' as a module directory.", &PathBuf::from(&path).display())]
+pub async fn read_module_dir(path: &Path) -> eros::Result<ModuleData> {
+    let config_path = path.join(MODULE_CONFIG_FILE_NAME);
+    let config = fs::read_to_string(&config_path)
+        .await
+        .with_context(|| format!("Could not read '{}'.", config_path.display()))?;
+    let mut containerfile_blocks = Vec::new();
+    for file_name in [MODULE_CONTAINERFILE_NAME, MODULE_INSTALL_CONTAINERFILE_NAME] {
+        let containerfile_path = path.join(file_name);
+        if !containerfile_path.is_file() {
+            if file_name == MODULE_CONTAINERFILE_NAME {
+                bail!("Could not find '{}'.", containerfile_path.display());
+            }
+            continue;
+        }
+        let data = fs::read_to_string(&containerfile_path)
+            .await
+            .with_context(|| format!("Could not read '{}'.", containerfile_path.display()))?;
+        containerfile_blocks.push(ContainerfileBlock {
+            role: None,
+            span: 0..data.len(),
+            line: 1,
+            data,
+            path: containerfile_path,
+        });
+    }
+    Ok(ModuleData {
+        containerfile_blocks,
+        config,
+        config_line: Some(1),
+        config_path,
+    })
+}
+
+/// A fenced code block that has been opened, but not yet closed.
+struct OpenBlock {
+    info: String,
+    /// Byte offsets of the whole block, including the fences.
+    span: Range<usize>,
+    content: String,
+}
+
+/// Parses a module file as CommonMark. Fenced `yaml`/`yml` blocks are config and fenced
+/// `containerfile`/`dockerfile` blocks are Containerfile parts. Any other block is documentation.
+fn parse_module_file(path: &Path, data: String) -> eros::Result<ModuleData> {
+    let mut containerfile_blocks: Vec<ContainerfileBlock> = Vec::new();
+    // Content, and the byte offsets of the whole block
+    let mut config: Option<(String, Range<usize>)> = None;
+    let mut open_block: Option<OpenBlock> = None;
+    for (event, span) in Parser::new(&data).into_offset_iter() {
+        match event {
+            Event::Start(Tag::CodeBlock(CodeBlockKind::Fenced(info))) => {
+                open_block = Some(OpenBlock {
+                    info: info.into_string(),
+                    span,
+                    content: String::new(),
+                });
+            }
+            Event::Text(text) => {
+                if let Some(block) = open_block.as_mut() {
+                    block.content.push_str(&text);
+                }
+            }
+            Event::End(TagEnd::CodeBlock) => {
+                // Indented code blocks are never opened, they are always documentation.
+                let Some(block) = open_block.take() else {
+                    continue;
+                };
+                let block_location = location(path, &data, block.span.start);
+                let block_data = &data[block.span.clone()];
+                if !is_closed(block_data) {
+                    bail!("{}: Code fence is never closed.", block_location);
+                }
+                // Content is between the opening fence line and the closing fence line.
+                let content_start = block.span.start
+                    + block_data
+                        .find('\n')
+                        .map_or(block.span.len(), |index| index + 1);
+                let content_end = block.span.start
+                    + block_data
+                        .trim_end()
+                        .rfind('\n')
+                        .map_or(0, |index| index + 1);
+                let content_span = content_start..content_end.max(content_start);
+                let mut info = info_string_words(&block.info).into_iter();
+                let language = info.next().map(|language| language.to_lowercase());
+                match language.as_deref() {
+                    Some("yaml") | Some("yml") => {
+                        if let Some((_, config_block_span)) = &config {
+                            eprintln!(
+                                "Warning: {}: Ignoring config block, the config is already declared at {}.",
+                                block_location,
+                                location(path, &data, config_block_span.start)
+                            );
+                            continue;
+                        }
+                        config = Some((block.content, block.span));
+                    }
+                    Some("containerfile") | Some("dockerfile") => {
+                        let (role, ignored) =
+                            BlockRole::from_attributes(info).with_context(|| {
+                                format!("{}: Invalid info string `{}`.", block_location, block.info)
+                            })?;
+                        for attribute in ignored {
+                            eprintln!(
+                                "Warning: {}: Ignoring attribute '{}', it is not a block role. Expected `stage=<name>`, `install` or `final`.",
+                                block_location, attribute
+                            );
+                        }
+                        containerfile_blocks.push(ContainerfileBlock {
+                            role,
+                            data: block.content,
+                            line: line_and_column(&data, content_start).0,
+                            span: content_span,
+                            path: path.to_path_buf(),
+                        });
+                    }
+                    Some(_) => {
+                        // Another documentation block
+                    }
+                    None => {
+                        eprintln!(
+                            "Warning: {}: Ignoring code block without a language. Use `containerfile`/`dockerfile` if it is part of the module, or any other language to mark it as documentation.",
+                            block_location
+                        );
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(match (containerfile_blocks.is_empty(), config) {
+        (true, None) => {
+            // No sections found for either so interpret the entire file as a containerfile
+            ModuleData {
+                containerfile_blocks: vec![ContainerfileBlock {
+                    role: None,
+                    span: 0..data.len(),
+                    line: 1,
+                    data,
+                    path: path.to_path_buf(),
+                }],
+                config: String::new(),
+                config_line: None,
+                config_path: path.to_path_buf(),
+            }
+        }
+        (true, Some((_, config_block_span))) => {
+            bail!(
+                "{}: Found config in the module file, but no containerfile data",
+                location(path, &data, config_block_span.start)
+            )
+        }
+        (false, config) => {
+            let (config, config_block_span) = config.unzip();
+            ModuleData {
+                containerfile_blocks,
+                config: config.unwrap_or_default(),
+                // The content starts on the line after the opening fence.
+                config_line: config_block_span.map(|span| line_and_column(&data, span.start).0 + 1),
+                config_path: path.to_path_buf(),
+            }
+        }
+    })
+}
+
+/// The whitespace separated words of an info string. Whitespace inside double quotes does not
+/// separate words, e.g. `title="Base image"` is one word.
+fn info_string_words(info: &str) -> Vec<&str> {
+    let mut words = Vec::new();
+    let mut start = None;
+    let mut in_quotes = false;
+    for (index, c) in info.char_indices() {
+        if c.is_whitespace() && !in_quotes {
+            if let Some(start) = start.take() {
+                words.push(&info[start..index]);
+            }
+            continue;
+        }
+        if c == '"' {
+            in_quotes = !in_quotes;
+        }
+        start.get_or_insert(index);
+    }
+    if let Some(start) = start {
+        words.push(&info[start..]);
+    }
+    words
+}
+
+/// Whether a fenced code block ends with a closing fence. CommonMark closes unclosed blocks at the
+/// end of the document, which is almost certainly a mistake in a module file.
+fn is_closed(block: &str) -> bool {
+    let block = block.trim();
+    let fence_char = block.chars().next().expect("Blocks start with a fence");
+    let fence_len = block.chars().take_while(|c| *c == fence_char).count();
+    let mut lines = block.lines();
+    lines.next();
+    lines.last().is_some_and(|last| {
+        let last = last.trim();
+        last.chars().all(|c| c == fence_char) && last.chars().count() >= fence_len
+    })
+}
+
+/// Line and column of a byte offset, both starting at 1.
+fn line_and_column(data: &str, offset: usize) -> (usize, usize) {
+    let before = &data[..offset];
+    let line = before.matches('\n').count() + 1;
+    let line_start = before.rfind('\n').map_or(0, |index| index + 1);
+    let column = before[line_start..].chars().count() + 1;
+    (line, column)
+}
+
+/// `file:line:column` of a byte offset.
+fn location(path: &Path, data: &str, offset: usize) -> String {
+    let (line, column) = line_and_column(data, offset);
+    format!("{}:{}:{}", path.display(), line, column)
+}
@@ -0,0 +1,182 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::LazyLock;
+
+use regex::Regex;
+use serde::Serialize;
+
+use crate::build::{Output, OutputPart, SourceInfoKind};
+
+/// Version of the `.yardmap` format, increased on breaking changes.
+const SOURCE_MAP_VERSION: u32 = 1;
+
+/// Extension of the source map written next to each output.
+pub const SOURCE_MAP_EXTENSION: &str = "yardmap";
+
+const REDACTED: &str = "<redacted>";
+
+/// A module instance of an output, as recorded in the provenance header and labels.
+pub struct ProvenanceModule<'a> {
+    pub name: &'a str,
+    pub source: &'a SourceInfoKind,
+    pub args: &'a BTreeMap<String, String>,
+}
+
+/// The comment block put at the start of an output, listing the yard version, the remotes and
+/// commits, and the resolved args of its modules.
+pub fn header(modules: &[ProvenanceModule]) -> String {
+    let mut header = format!("# Generated by yard {}\n", env!("CARGO_PKG_VERSION"));
+    let remotes = remotes(modules);
+    if !remotes.is_empty() {
+        header.push_str("#\n# Remotes:\n");
+        for (url, commit) in remotes {
+            header.push_str(&format!("#   {} @ {}\n", redact_url(url), commit));
+        }
+    }
+    header.push_str("#\n# Modules:\n");
+    for module in modules {
+        header.push_str(&format!(
+            "#   {}: {}\n",
+            module.name,
+            describe_source(module.source)
+        ));
+        for (arg, value) in module.args {
+            header.push_str(&format!("#     {}={}\n", arg, value.replace('\n', "\\n")));
+        }
+    }
+    header.push('\n');
+    header
+}
+
+/// A `LABEL` instruction with the `org.opencontainers.image.*` and `io.containeryard.*` labels of
+/// an output. `source` and `revision` are only set if the remote modules of the output all come
+/// from the same remote.
+pub fn labels(output_name: &str, profile: Option<&str>, modules: &[ProvenanceModule]) -> String {
+    let remotes = remotes(modules);
+    let mut labels: Vec<(&str, String)> =
+        vec![("org.opencontainers.image.title", output_name.to_owned())];
+    if let [(url, commit)] = remotes.as_slice() {
+        labels.push(("org.opencontainers.image.source", redact_url(url)));
+        labels.push(("org.opencontainers.image.revision", (*commit).to_owned()));
+    }
+    labels.push((
+        "io.containeryard.version",
+        env!("CARGO_PKG_VERSION").to_owned(),
+    ));
+    if let Some(profile) = profile {
+        labels.push(("io.containeryard.profile", profile.to_owned()));
+    }
+    let mut module_names: Vec<&str> = Vec::new();
+    for module in modules {
+        if !matches!(module.source, SourceInfoKind::Inline(_))
+            && !module_names.contains(&module.name)
+        {
+            module_names.push(module.name);
+        }
+    }
+    if !module_names.is_empty() {
+        labels.push(("io.containeryard.modules", module_names.join(",")));
+    }
+    if !remotes.is_empty() {
+        let remotes: Vec<String> = remotes
+            .iter()
+            .map(|(url, commit)| format!("{}@{}", redact_url(url), commit))
+            .collect();
+        labels.push(("io.containeryard.remotes", remotes.join(",")));
+    }
+    let labels: Vec<String> = labels
+        .into_iter()
+        .map(|(key, value)| {
+            format!(
+                "{}=\"{}\"",
+                key,
+                value.replace('\\', "\\\\").replace('"', "\\\"")
+            )
+        })
+        .collect();
+    format!("LABEL {}", labels.join(" \\\n      "))
+}
+
+/// The unique remotes of the modules, as url and commit, in the order they are first used.
+fn remotes<'a>(modules: &[ProvenanceModule<'a>]) -> Vec<(&'a str, &'a str)> {
+    let mut remotes: Vec<(&str, &str)> = Vec::new();
+    for module in modules {
+        if let SourceInfoKind::Remote(info) = module.source
+            && !remotes.contains(&(info.url.as_str(), info.commit.as_str()))
+        {
+            remotes.push((&info.url, &info.commit));
+        }
+    }
+    remotes
+}
+
+/// Where a module comes from, in one line.
+pub fn describe_source(source: &SourceInfoKind) -> String {
+    match source {
+        SourceInfoKind::Local(info) => info.path.clone(),
+        SourceInfoKind::Remote(info) => format!(
+            "{} ({} @ {})",
+            info.path,
+            redact_url(&info.url),
+            info.commit
+        ),
+        SourceInfoKind::Inline(_) => "~INLINE~".to_owned(),
+    }
+}
+
+/// The args of a module instance, ordered by name, with the values of args that look like secrets
+/// and the credentials of urls redacted.
+pub fn redacted_args(args: &HashMap<String, String>) -> BTreeMap<String, String> {
+    // Whole `_` or `-` separated words, so e.g. `author` is kept
+    static SECRET_ARG_REGEX: LazyLock<Regex> = LazyLock::new(|| {
+        Regex::new(
+            r"(?i)(^|[_-])(auth|token|secret|password|passwd|credentials?|api_?key|private_?key)([_-]|$)",
+        )
+        .expect("Invalid regex")
+    });
+    args.iter()
+        .map(|(arg, value)| {
+            let value = if SECRET_ARG_REGEX.is_match(arg) {
+                REDACTED.to_owned()
+            } else {
+                redact_url(value)
+            };
+            (arg.clone(), value)
+        })
+        .collect()
+}
+
+/// Replaces the `user:password@` part of the urls in `value`.
+fn redact_url(value: &str) -> String {
+    static URL_CREDENTIALS_REGEX: LazyLock<Regex> =
+        LazyLock::new(|| Regex::new(r"://[^/\s@]+@").expect("Invalid regex"));
+    URL_CREDENTIALS_REGEX
+        .replace_all(value, format!("://{REDACTED}@"))
+        .into_owned()
+}
+
+#[derive(Serialize)]
+struct SourceMap<'a> {
+    version: u32,
+    /// Output path, relative to the output directory
+    output: &'a Path,
+    parts: &'a [OutputPart],
+}
+
+/// The path, relative to the output directory, and content of the source map of `output`.
+pub fn source_map(output: &Output) -> (PathBuf, String) {
+    let source_map_path = PathBuf::from(format!(
+        "{}.{}",
+        output.path.display(),
+        SOURCE_MAP_EXTENSION
+    ));
+    let source_map = SourceMap {
+        version: SOURCE_MAP_VERSION,
+        output: &output.path,
+        parts: &output.parts,
+    };
+    let mut content =
+        serde_json::to_string_pretty(&source_map).expect("Source maps are serializable");
+    content.push('\n');
+    (source_map_path, content)
+}
@@ -1,11 +1,17 @@
-use std::{collections::HashMap, path::PathBuf};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
 
 use eros::{Context, bail};
 use regex::Regex;
 use tokio::{fs, process::Command};
 use tracing::trace;
 
-use crate::build::{ModuleData, RemoteModuleInfo, SourceInfoKind, read_module_file};
+use crate::build::{RemoteModuleInfo, SourceInfoKind};
+use crate::module_file::{
+    MODULE_CONFIG_FILE_NAME, MODULE_DIRECTORY_FILE_NAMES, ModuleData, read_module,
+};
 
 use super::{GitProvider, ModuleFileData, ReferenceInfo, path_in_cache_dir};
 
@@ -50,17 +56,50 @@ impl GitProvider for Git {
                 &self.repo_name,
                 &self.commit,
             );
-            if !module_path_cache.exists() {
+            // Required files of directory modules may already be cached in the module directory.
+            let is_cached = module_path_cache.is_file()
+                || module_path_cache.join(MODULE_CONFIG_FILE_NAME).is_file();
+            let is_directory = if is_cached {
+                module_path_cache.is_dir()
+            } else {
                 trace!(
                     "Module `{}` not found in cache. Retrieving from remote...",
                     name
                 );
-                self.retrieve_file_and_put_at(&module_path, &module_path_cache)
-                    .await?;
-            }
+                match self.list_remote_dir(&module_path).await? {
+                    Some(file_names) => {
+                        for file_name in MODULE_DIRECTORY_FILE_NAMES {
+                            if file_names.iter().any(|e| e == file_name) {
+                                self.retrieve_file_and_put_at(
+                                    &format!("{}/{}", module_path.trim_end_matches('/'), file_name),
+                                    &module_path_cache.join(file_name),
+                                )
+                                .await?;
+                            }
+                        }
+                        true
+                    }
+                    None => {
+                        self.retrieve_file_and_put_at(&module_path, &module_path_cache)
+                            .await?;
+                        false
+                    }
+                }
+            };
             assert!(module_path_cache.exists());
 
-            let module_data: ModuleData = read_module_file(&module_path_cache).await?;
+            let module_data: ModuleData = read_module(&module_path_cache).await?;
+            // Required files are relative to the parent of the path, so for directory modules the
+            // path is the config file in the directory.
+            let module_path = if is_directory {
+                format!(
+                    "{}/{}",
+                    module_path.trim_end_matches('/'),
+                    MODULE_CONFIG_FILE_NAME
+                )
+            } else {
+                module_path
+            };
 
             let source_info = SourceInfoKind::Remote(RemoteModuleInfo {
                 url: self.url.clone(),
@@ -73,9 +112,10 @@ impl GitProvider for Git {
             module_to_files.insert(
                 name,
                 ModuleFileData {
-                    containerfile_data: module_data.containerfile,
+                    containerfile_blocks: module_data.containerfile_blocks,
                     config_data: module_data.config,
-                    install_stage_data: module_data.install_stage,
+                    config_line: module_data.config_line,
+                    config_path: module_data.config_path,
                     source_info,
                 },
             );
@@ -94,6 +134,61 @@ impl GitProvider for Git {
     }
 
     async fn extract_remote_path_data(&self, remote_path: &str) -> eros::Result<String> {
+        let repo = self.checkout().await?;
+        let repo_dir = &repo.dir;
+
+        // get file data
+        let remote_file_path = repo_dir.join(remote_path);
+        if !remote_file_path.is_file() {
+            bail!(
+                "Could not find file at remote path `{}` in repo `{}` at commit `{}`",
+                &remote_path,
+                &self.url,
+                &self.commit
+            )
+        }
+
+        let file_data = fs::read_to_string(&remote_file_path)
+            .await
+            .map_err(|e| eros::error!(e))
+            .with_context(|| format!("Could not read `{}`", &remote_file_path.display()))?;
+
+        Ok(file_data)
+    }
+
+    async fn list_remote_dir(&self, remote_path: &str) -> eros::Result<Option<Vec<String>>> {
+        let repo = self.checkout().await?;
+        let repo_dir = &repo.dir;
+        let remote_dir_path = repo_dir.join(remote_path);
+        if !remote_dir_path.is_dir() {
+            return Ok(None);
+        }
+        let mut file_names = Vec::new();
+        let mut entries = fs::read_dir(&remote_dir_path)
+            .await
+            .with_context(|| format!("Could not read `{}`", &remote_dir_path.display()))?;
+        while let Some(entry) = entries.next_entry().await? {
+            file_names.push(entry.file_name().to_string_lossy().into_owned());
+        }
+        Ok(Some(file_names))
+    }
+
+    async fn fetch(&self) -> eros::Result<()> {
+        self.checkout().await.map(|_| ())
+    }
+}
+
+/// A repo checked out at a commit. Other checkouts of the repo, including by other processes, wait
+/// until this is dropped.
+struct CheckedOutRepo {
+    dir: PathBuf,
+    _lock: std::fs::File,
+}
+
+impl Git {
+    /// Ensures the repo is cloned and checked out at the commit. The repo is only fetched if the
+    /// commit is not already available locally.
+    async fn checkout(&self) -> eros::Result<CheckedOutRepo> {
         // Ensure repo is downloaded
         let provider_git_cache_dir = dirs::cache_dir()
             .expect("Could not determine cache directory of platform")
@@ -103,6 +198,18 @@ impl GitProvider for Git {
             .join(&self.provider)
             .join(&self.repo_owner);
         let repo_dir = provider_git_cache_dir.join(&self.repo_name);
+        fs::create_dir_all(&provider_git_cache_dir).await?;
+        let lock_path = provider_git_cache_dir.join(format!("{}.lock", self.repo_name));
+        // Waiting for the lock blocks, so it must not hold up the other tasks of the runtime
+        let lock = tokio::task::spawn_blocking(move || -> eros::Result<std::fs::File> {
+            let lock = std::fs::File::create(&lock_path)
+                .with_context(|| format!("Could not create lock file `{}`", lock_path.display()))?;
+            lock.lock()
+                .with_context(|| format!("Could not lock `{}`", lock_path.display()))?;
+            Ok(lock)
+        })
+        .await
+        .context("Could not wait for the lock of the repo")??;
         let mut will_clone = false;
         if repo_dir.is_dir() {
             if !repo_dir.join(".git").is_dir() {
@@ -146,6 +253,11 @@ impl GitProvider for Git {
                     String::from_utf8_lossy(&clone_output.stderr)
                 );
             }
+        } else if self.has_commit(&repo_dir).await {
+            trace!(
+                "Commit `{}` is already available in repo `{}`",
+                self.commit, self.url
+            );
         } else {
             trace!(
                 "Pulling git repo `{}` to `{}`",
@@ -204,23 +316,20 @@ impl GitProvider for Git {
             );
         }
 
-        // get file data
-        let remote_file_path = repo_dir.join(remote_path);
-        if !remote_file_path.is_file() {
-            bail!(
-                "Could not find file at remote path `{}` in repo `{}` at commit `{}`",
-                &remote_path,
-                &self.url,
-                &self.commit
-            )
-        }
+        Ok(CheckedOutRepo {
+            dir: repo_dir,
+            _lock: lock,
+        })
+    }
 
-        let file_data = fs::read_to_string(&remote_file_path)
+    /// Whether the commit is available in the cloned repo, without fetching.
+    async fn has_commit(&self, repo_dir: &Path) -> bool {
+        Command::new("git")
+            .args(["cat-file", "-e", &format!("{}^{{commit}}", self.commit)])
+            .current_dir(repo_dir)
+            .output()
             .await
-            .map_err(|e| eros::error!(e))
-            .with_context(|| format!("Could not read `{}`", &remote_file_path.display()))?;
-
-        Ok(file_data)
+            .is_ok_and(|output| output.status.success())
     }
 }
 
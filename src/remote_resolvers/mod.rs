@@ -20,6 +20,13 @@ pub struct ReferenceInfo<'a> {
     commit: &'a str,
 }
 
+impl<'a> ReferenceInfo<'a> {
+    /// Provider, owner and name of the repo. Remotes of the same repo share its clone in the cache
+    pub fn repo(&self) -> (&'a str, &'a str, &'a str) {
+        (self.provider, self.repo_owner, self.repo_name)
+    }
+}
+
 pub trait GitProvider {
     /// Downloads the module module file or gets from cache at the
     /// specified paths, and returns the raw data.
@@ -34,6 +41,12 @@ pub trait GitProvider {
     /// Downloads the file and returns the data as a [String]
     async fn extract_remote_path_data(&self, remote_path: &str) -> eros::Result<String>;
 
+    /// Returns the names of the files in the remote directory, or `None` if the path is not a directory
+    async fn list_remote_dir(&self, remote_path: &str) -> eros::Result<Option<Vec<String>>>;
+
+    /// Ensures the commit is available locally, so later retrievals do not need to fetch
+    async fn fetch(&self) -> eros::Result<()>;
+
     /// Downloads the file or gets from cache and returns the data as a [String]. Caches locally if the
     /// data is downloaded for the first time
     async fn extract_remote_path_data_save_save_to_cache(
@@ -114,6 +127,18 @@ impl GitProvider for GitProviderKind {
         }
     }
 
+    async fn list_remote_dir(&self, remote_path: &str) -> eros::Result<Option<Vec<String>>> {
+        match self {
+            GitProviderKind::Git(git) => git.list_remote_dir(remote_path).await,
+        }
+    }
+
+    async fn fetch(&self) -> eros::Result<()> {
+        match self {
+            GitProviderKind::Git(git) => git.fetch().await,
+        }
+    }
+
     async fn extract_remote_path_data_save_save_to_cache(
         &self,
         remote_path: &str,
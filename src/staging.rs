@@ -0,0 +1,102 @@
+use std::path::{Path, PathBuf};
+
+use eros::Context;
+use tokio::fs;
+
+/// Prefix of the staging directory created in the output directory.
+const STAGING_DIR_PREFIX: &str = ".yard-staging-";
+
+/// Writes all files and removes all `removed` files, relative to `output_dir`, or none of them.
+/// Files are staged in a temporary directory inside `output_dir`, so they are on the same file
+/// system, then moved into place. If a file can not be moved, the files already moved are restored.
+pub async fn write_all(
+    output_dir: &Path,
+    files: &[(&Path, &[u8])],
+    removed: &[&Path],
+) -> eros::Result<()> {
+    fs::create_dir_all(output_dir).await.with_context(|| {
+        format!(
+            "Could not create the output directory '{}'.",
+            output_dir.display()
+        )
+    })?;
+    let staging_dir = tempfile::Builder::new()
+        .prefix(STAGING_DIR_PREFIX)
+        .tempdir_in(output_dir)
+        .with_context(|| {
+            format!(
+                "Could not create a staging directory in '{}'.",
+                output_dir.display()
+            )
+        })?;
+    let staged_path = |index: usize| staging_dir.path().join(format!("new-{index}"));
+    let backup_path = |index: usize| staging_dir.path().join(format!("old-{index}"));
+    for (index, (file_path, content)) in files.iter().enumerate() {
+        fs::write(staged_path(index), content)
+            .await
+            .with_context(|| format!("Could not stage '{}'.", file_path.display()))?;
+    }
+
+    // Target path and, if it existed, the path of its previous content
+    let mut moved: Vec<(PathBuf, Option<PathBuf>)> = Vec::with_capacity(files.len());
+    for (index, (file_path, _)) in files.iter().enumerate() {
+        let target_path = output_dir.join(file_path);
+        let result = async {
+            if let Some(parent) = target_path.parent() {
+                fs::create_dir_all(parent).await?;
+            }
+            let backup = if fs::try_exists(&target_path).await? {
+                fs::rename(&target_path, backup_path(index)).await?;
+                Some(backup_path(index))
+            } else {
+                None
+            };
+            moved.push((target_path.clone(), backup));
+            fs::rename(staged_path(index), &target_path).await
+        }
+        .await;
+        if result.is_err() {
+            restore(&moved).await;
+        }
+        result.with_context(|| {
+            format!(
+                "Could not write '{}'. No files were changed.",
+                target_path.display()
+            )
+        })?;
+    }
+    for (index, file_path) in removed.iter().enumerate() {
+        let target_path = output_dir.join(file_path);
+        let removed_path = staging_dir.path().join(format!("removed-{index}"));
+        let result = async {
+            if !fs::try_exists(&target_path).await? {
+                return Ok(());
+            }
+            fs::rename(&target_path, &removed_path).await?;
+            // Restoring moves the removed file back
+            moved.push((target_path.clone(), Some(removed_path.clone())));
+            Ok::<(), std::io::Error>(())
+        }
+        .await;
+        if result.is_err() {
+            restore(&moved).await;
+        }
+        result.with_context(|| {
+            format!(
+                "Could not remove '{}'. No files were changed.",
+                target_path.display()
+            )
+        })?;
+    }
+    Ok(())
+}
+
+/// Restores the previous content of the moved files, removing the files that did not exist.
+async fn restore(moved: &[(PathBuf, Option<PathBuf>)]) {
+    for (target_path, backup) in moved.iter().rev() {
+        let _ = match backup {
+            Some(backup) => fs::rename(backup, target_path).await,
+            None => fs::remove_file(target_path).await,
+        };
+    }
+}
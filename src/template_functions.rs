@@ -0,0 +1,199 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use sha2::{Digest, Sha256};
+use tera::{Error, Kwargs, Map, State, Tera, TeraResult, Value};
+
+use crate::build::is_local_absolute;
+
+/// Registers the yard specific filters and functions. `files` are the required files of the
+/// build, relative to the build context. They are the only files `sha256` hashes.
+pub fn register(tera: &mut Tera, files: HashMap<PathBuf, Vec<u8>>) {
+    tera.register_filter("shell_quote", |value: &str, _: Kwargs, _: &State| {
+        shell_quote(value)
+    });
+    tera.register_filter(
+        "join_lines",
+        |values: Vec<String>, kwargs: Kwargs, _: &State| -> TeraResult<String> {
+            let indent = kwargs.get::<usize>("indent")?.unwrap_or(4);
+            Ok(join_lines(&values, indent))
+        },
+    );
+    tera.register_function(
+        "apt_install",
+        |kwargs: Kwargs, _: &State| -> TeraResult<String> {
+            let packages = kwargs.must_get::<Vec<String>>("packages")?;
+            Ok(apt_install(&packages))
+        },
+    );
+    tera.register_function(
+        "env_block",
+        |kwargs: Kwargs, _: &State| -> TeraResult<String> {
+            let vars = kwargs.must_get::<&Map>("vars")?;
+            instruction_block("ENV", vars)
+        },
+    );
+    tera.register_function(
+        "arg_block",
+        |kwargs: Kwargs, _: &State| -> TeraResult<String> {
+            let args = kwargs.must_get::<&Map>("args")?;
+            instruction_block("ARG", args)
+        },
+    );
+    tera.register_function(
+        "sha256",
+        move |kwargs: Kwargs, _: &State| -> TeraResult<String> {
+            let path = kwargs.must_get::<&str>("path")?;
+            sha256(&files, path)
+        },
+    );
+}
+
+/// Quotes a value for POSIX shells. Values that only contain safe characters are left as is.
+fn shell_quote(value: &str) -> String {
+    let is_safe = |c: char| c.is_ascii_alphanumeric() || "_@%+=:,./-".contains(c);
+    if !value.is_empty() && value.chars().all(is_safe) {
+        return value.to_owned();
+    }
+    format!("'{}'", value.replace('\'', r"'\''"))
+}
+
+/// Joins values with `\` line continuations, indenting each continued line.
+fn join_lines(values: &[String], indent: usize) -> String {
+    values.join(&format!(" \\\n{}", " ".repeat(indent)))
+}
+
+/// A `RUN` instruction installing the packages with `apt-get`, and cleaning up afterwards.
+fn apt_install(packages: &[String]) -> String {
+    let packages: Vec<String> = packages.iter().map(|e| shell_quote(e)).collect();
+    format!(
+        "RUN apt-get update \\\n    && apt-get install -y --no-install-recommends \\\n        {} \\\n    && rm -rf /var/lib/apt/lists/*",
+        join_lines(&packages, 8)
+    )
+}
+
+/// An `ENV` or `ARG` instruction per entry, sorted by key. `ARG`s with a `none` value have no
+/// default.
+fn instruction_block(instruction: &str, entries: &Map) -> TeraResult<String> {
+    let mut entries: Vec<(String, &Value)> = entries
+        .iter()
+        .map(|(key, value)| (key.to_string(), value))
+        .collect();
+    entries.sort_by(|(key1, _), (key2, _)| key1.cmp(key2));
+    let mut lines = Vec::with_capacity(entries.len());
+    for (key, value) in entries {
+        if value.is_none() && instruction == "ARG" {
+            lines.push(format!("{instruction} {key}"));
+            continue;
+        }
+        if value.is_map() || value.is_array() || value.is_none() {
+            return Err(Error::message(format!(
+                "Value for '{key}' must be a string, number or boolean."
+            )));
+        }
+        let value = value.to_string().replace('\\', r"\\").replace('"', "\\\"");
+        lines.push(format!("{instruction} {key}=\"{value}\""));
+    }
+    Ok(lines.join("\n"))
+}
+
+/// Hex encoded sha256 of the required file at `path`, relative to the build context. Other files,
+/// e.g. the yard file or files outside the build context, are never read.
+fn sha256(files: &HashMap<PathBuf, Vec<u8>>, path: &str) -> TeraResult<String> {
+    let relative_path = PathBuf::from(path);
+    is_local_absolute(&relative_path).map_err(|e| Error::message(e.to_string()))?;
+    let Some(data) = files.get(&relative_path) else {
+        return Err(Error::message(format!(
+            "'{path}' is not a required file of the build. `sha256` only hashes the `required_files` declared by modules."
+        )));
+    };
+    Ok(format!("{:x}", Sha256::digest(data)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(template: &str) -> TeraResult<String> {
+        let mut tera = Tera::default();
+        let files = HashMap::from([(PathBuf::from("pending.txt"), b"pending".to_vec())]);
+        register(&mut tera, files);
+        tera.render_str(template, &tera::Context::new(), false)
+    }
+
+    #[test]
+    fn shell_quote_leaves_safe_values() {
+        assert_eq!(shell_quote("abc-1.2/x"), "abc-1.2/x");
+    }
+
+    #[test]
+    fn shell_quote_quotes_unsafe_values() {
+        assert_eq!(shell_quote(""), "''");
+        assert_eq!(shell_quote("a b"), "'a b'");
+        assert_eq!(shell_quote("it's"), r"'it'\''s'");
+        assert_eq!(
+            render("{{ 'a $HOME' | shell_quote }}").unwrap(),
+            "'a $HOME'"
+        );
+    }
+
+    #[test]
+    fn join_lines_adds_continuations() {
+        assert_eq!(
+            render(r#"{{ ["a", "b", "c"] | join_lines }}"#).unwrap(),
+            "a \\\n    b \\\n    c"
+        );
+        assert_eq!(
+            render(r#"{{ ["a", "b"] | join_lines(indent=2) }}"#).unwrap(),
+            "a \\\n  b"
+        );
+    }
+
+    #[test]
+    fn apt_install_creates_run_instruction() {
+        assert_eq!(
+            render(r#"{{ apt_install(packages=["git", "curl"]) }}"#).unwrap(),
+            "RUN apt-get update \\
+    && apt-get install -y --no-install-recommends \\
+        git \\
+        curl \\
+    && rm -rf /var/lib/apt/lists/*"
+        );
+    }
+
+    #[test]
+    fn env_block_is_sorted_and_escaped() {
+        assert_eq!(
+            render(
+                r#"{{ env_block(vars={"PATH": "/bin", "GREETING": "say \"hi\"", "DEBUG": 1}) }}"#
+            )
+            .unwrap(),
+            "ENV DEBUG=\"1\"\nENV GREETING=\"say \\\"hi\\\"\"\nENV PATH=\"/bin\""
+        );
+        assert!(render(r#"{{ env_block(vars={"A": [1]}) }}"#).is_err());
+    }
+
+    #[test]
+    fn arg_block_allows_no_default() {
+        assert_eq!(
+            render(r#"{{ arg_block(args={"VERSION": "1.0", "USER": none}) }}"#).unwrap(),
+            "ARG USER\nARG VERSION=\"1.0\""
+        );
+    }
+
+    #[test]
+    fn sha256_rejects_files_that_are_not_required() {
+        // Exists on disk, but is not a required file
+        assert!(render(r#"{{ sha256(path="Cargo.toml") }}"#).is_err());
+        assert!(render(r#"{{ sha256(path="../pending.txt") }}"#).is_err());
+        assert!(render(r#"{{ sha256(path="/etc/passwd") }}"#).is_err());
+    }
+
+    #[test]
+    fn sha256_hashes_required_file() {
+        assert_eq!(
+            render(r#"{{ sha256(path="pending.txt") }}"#).unwrap(),
+            format!("{:x}", Sha256::digest(b"pending"))
+        );
+    }
+}
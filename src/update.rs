@@ -6,13 +6,10 @@ use std::{io, str};
 
 use eros::{bail, Context};
 
-use crate::build::YARD_YAML_FILE_NAME;
-
 /// Updates the `yard.yaml` file's "commit: <sha>" for each entry in the remote. Does not modify any other parts of the file
 /// Even saves comments if they exist on the comment line e.g. "commit: <sha> comment"
-pub fn update(path: &Path) -> eros::Result<()> {
-    let yard_file = path.join(YARD_YAML_FILE_NAME);
-    let input_file = File::open(&yard_file)?;
+pub fn update(yard_file: &Path) -> eros::Result<()> {
+    let input_file = File::open(yard_file)?;
     let reader = io::BufReader::new(input_file);
 
     let mut lines: Vec<String> = Vec::new();
@@ -39,7 +36,8 @@ pub fn update(path: &Path) -> eros::Result<()> {
                 latest_commit = get_latest_commit_sha(&current_repo_url).with_context(|| {
                     format!(
                         "Failure occurred at line number '{}' in {}",
-                        line_number, YARD_YAML_FILE_NAME
+                        line_number,
+                        yard_file.display()
                     )
                 })?
             }
@@ -72,7 +70,7 @@ pub fn update(path: &Path) -> eros::Result<()> {
         }
     }
 
-    std::fs::write(&yard_file, lines.join("\n"))?;
+    std::fs::write(yard_file, lines.join("\n"))?;
 
     Ok(())
 }
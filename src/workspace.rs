@@ -0,0 +1,288 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use clap::ValueEnum;
+use eros::{Context, bail};
+use futures::future::join_all;
+use indexmap::IndexMap;
+use jsonschema::{Draft, Validator};
+use serde::Deserialize;
+use tokio::{fs, process::Command};
+
+use crate::build::{YARD_YAML_FILE_NAME, YamlRemote, validate_against_schema};
+use crate::manifest::ManifestFormat;
+use crate::remote_resolvers::{GitProvider, GitProviderKind, create_provider};
+
+pub const WORKSPACE_FILE_NAME: &str = "yard-workspace.yaml";
+
+/// Created using the yard-workspace-schema.json file
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct YamlWorkspace {
+    /// Paths or glob patterns of member directories, relative to the workspace root
+    pub members: Vec<String>,
+}
+
+/// A directory of the workspace containing a `yard.yaml`.
+struct Member {
+    /// Path relative to the workspace root, used in output and errors
+    name: String,
+    path: PathBuf,
+}
+
+pub async fn build_workspace(
+    root: &Path,
+    do_not_refetch: bool,
+    with_cache_busting: bool,
+    check: bool,
+    emit_manifest: Option<ManifestFormat>,
+    profile: Option<&str>,
+) -> eros::Result<()> {
+    let members = load_members(root).await?;
+    fetch_remotes(&members).await?;
+    let mut args = vec!["build".to_owned()];
+    if do_not_refetch {
+        args.push("--do-not-refetch".to_owned());
+    }
+    if with_cache_busting {
+        args.push("--with-cache-busting".to_owned());
+    }
+    if check {
+        args.push("--check".to_owned());
+    }
+    if let Some(format) = emit_manifest {
+        let format = format
+            .to_possible_value()
+            .expect("Manifest formats are not skipped");
+        args.extend(["--emit-manifest".to_owned(), format.get_name().to_owned()]);
+    }
+    if let Some(profile) = profile {
+        args.extend(["--profile".to_owned(), profile.to_owned()]);
+    }
+    for (_, stdout) in run_in_members(&members, &args).await? {
+        print!("{stdout}");
+    }
+    Ok(())
+}
+
+pub async fn update_workspace(root: &Path) -> eros::Result<()> {
+    let members = load_members(root).await?;
+    run_in_members(&members, &["update".to_owned()]).await?;
+    Ok(())
+}
+
+/// Output filenames of every member, relative to the workspace root, in the order members and
+/// outputs are declared.
+pub async fn workspace_output_order(
+    root: &Path,
+    profile: Option<&str>,
+) -> eros::Result<Vec<String>> {
+    let members = load_members(root).await?;
+    let mut args = vec!["outputs".to_owned()];
+    if let Some(profile) = profile {
+        args.extend(["--profile".to_owned(), profile.to_owned()]);
+    }
+    let mut output_order = Vec::new();
+    for (member, stdout) in run_in_members(&members, &args).await? {
+        output_order.extend(
+            stdout
+                .lines()
+                .map(|output| format!("{}/{}", member.name, output)),
+        );
+    }
+    Ok(output_order)
+}
+
+fn workspace_validator() -> Validator {
+    let workspace_schema: &'static str = include_str!("./schemas/yard-workspace-schema.json");
+    let workspace_schema: serde_json::Value = serde_json::from_str(workspace_schema)
+        .expect("yard-workspace-schema.json is not valid json");
+    Validator::options()
+        .with_draft(Draft::Draft7)
+        .build(&workspace_schema)
+        .expect("yard-workspace-schema.json is not a valid json schema")
+}
+
+/// Member directories, in the order their patterns are declared. Matches of a glob pattern are
+/// sorted.
+#[eros::context("Could not load the workspace at '{}'.", root.display())]
+async fn load_members(root: &Path) -> eros::Result<Vec<Member>> {
+    let workspace_file_path = root.join(WORKSPACE_FILE_NAME);
+    let workspace_file_data = fs::read_to_string(&workspace_file_path)
+        .await
+        .with_context(|| format!("Could not read '{}'.", workspace_file_path.display()))?;
+    let workspace_yaml: serde_yaml::Value = serde_yaml::from_str(&workspace_file_data)
+        .with_context(|| format!("{} is not valid yaml.", workspace_file_path.display()))?;
+    validate_against_schema(&workspace_validator(), &workspace_yaml)
+        .with_context(|| format!("For path '{}'.", workspace_file_path.display()))?;
+    let workspace: YamlWorkspace = serde_yaml::from_value(workspace_yaml)?;
+    let mut members: Vec<Member> = Vec::new();
+    for pattern in workspace.members {
+        let full_pattern = root.join(&pattern);
+        let paths = glob::glob(&full_pattern.to_string_lossy())
+            .with_context(|| format!("Member '{}' is not a valid glob pattern.", pattern))?;
+        let mut member_paths: Vec<PathBuf> = paths
+            .filter_map(Result::ok)
+            .filter(|path| path.join(YARD_YAML_FILE_NAME).is_file())
+            .collect();
+        if member_paths.is_empty() {
+            bail!(
+                "Member '{}' does not match any directory containing a '{}'.",
+                pattern,
+                YARD_YAML_FILE_NAME
+            );
+        }
+        member_paths.sort();
+        for path in member_paths {
+            if members.iter().any(|member| member.path == path) {
+                continue;
+            }
+            let name = path
+                .strip_prefix(root)
+                .unwrap_or(&path)
+                .to_string_lossy()
+                .into_owned();
+            members.push(Member { name, path });
+        }
+    }
+    Ok(members)
+}
+
+/// Fetches every remote declared by the members once, so members do not fetch the same remote in
+/// parallel. Remotes declared by included yard files are fetched by the members.
+async fn fetch_remotes(members: &[Member]) -> eros::Result<()> {
+    // url, commit, and the names of the members that declare it
+    let mut remotes: Vec<(String, String, Vec<&str>)> = Vec::new();
+    for member in members {
+        let yard_file_path = member.path.join(YARD_YAML_FILE_NAME);
+        let yard_file_data = fs::read_to_string(&yard_file_path)
+            .await
+            .with_context(|| format!("Could not read '{}'.", yard_file_path.display()))?;
+        let yard_yaml: serde_yaml::Value = serde_yaml::from_str(&yard_file_data)
+            .with_context(|| format!("{} is not valid yaml.", yard_file_path.display()))?;
+        let Some(member_remotes) = yard_yaml
+            .get("inputs")
+            .and_then(|inputs| inputs.get("remotes"))
+        else {
+            continue;
+        };
+        let member_remotes: Vec<YamlRemote> = serde_yaml::from_value(member_remotes.clone())
+            .with_context(|| format!("Invalid remotes in '{}'.", yard_file_path.display()))?;
+        for remote in member_remotes {
+            match remotes
+                .iter_mut()
+                .find(|(url, commit, _)| *url == remote.url && *commit == remote.commit)
+            {
+                Some((_, _, member_names)) => member_names.push(&member.name),
+                None => remotes.push((remote.url, remote.commit, vec![&member.name])),
+            }
+        }
+    }
+    let providers: Vec<eros::Result<GitProviderKind>> = remotes
+        .iter()
+        .map(|(url, commit, _)| create_provider(url.clone(), commit.clone()))
+        .collect();
+    // Remotes of the same repo share its clone in the cache, so they are fetched one after another
+    let mut repo_to_providers: IndexMap<_, Vec<_>> = IndexMap::new();
+    for (index, provider) in providers.iter().enumerate() {
+        if let Ok(provider) = provider {
+            repo_to_providers
+                .entry(provider.reference_info().repo())
+                .or_default()
+                .push((index, provider));
+        }
+    }
+    let mut index_to_result: HashMap<usize, eros::Result<()>> =
+        join_all(repo_to_providers.into_values().map(|providers| async move {
+            let mut results = Vec::with_capacity(providers.len());
+            for (index, provider) in providers {
+                results.push((index, provider.fetch().await));
+            }
+            results
+        }))
+        .await
+        .into_iter()
+        .flatten()
+        .collect();
+    let results = providers
+        .into_iter()
+        .enumerate()
+        .map(|(index, provider)| match provider {
+            Ok(_) => index_to_result
+                .remove(&index)
+                .expect("Every provider is fetched"),
+            Err(error) => Err(error),
+        });
+    let mut failures = Vec::new();
+    for ((url, commit, member_names), result) in remotes.iter().zip(results) {
+        if let Err(error) = result {
+            failures.push(format!(
+                "Remote '{}' at commit '{}', declared by '{}':\n{:?}",
+                url,
+                commit,
+                member_names.join("', '"),
+                error
+            ));
+        }
+    }
+    if !failures.is_empty() {
+        bail!(
+            "Could not fetch {} of {} remotes.\n\n{}",
+            failures.len(),
+            remotes.len(),
+            failures.join("\n\n")
+        );
+    }
+    Ok(())
+}
+
+/// Runs `yard <args>` in every member directory in parallel, returning the stdout of each member.
+/// The stderr of every member, e.g. warnings, is forwarded. If any member fails, the stdout of
+/// every member is printed, then the failing members are reported.
+async fn run_in_members<'a>(
+    members: &'a [Member],
+    args: &[String],
+) -> eros::Result<Vec<(&'a Member, String)>> {
+    let yard = std::env::current_exe().context("Could not determine the path of `yard`.")?;
+    let results = join_all(members.iter().map(|member| {
+        Command::new(&yard)
+            .args(args)
+            .current_dir(&member.path)
+            .output()
+    }))
+    .await;
+    let mut stdouts = Vec::with_capacity(members.len());
+    let mut failed_members = Vec::new();
+    for (member, result) in members.iter().zip(results) {
+        let (stdout, stderr) = match result {
+            Ok(output) => {
+                if !output.status.success() {
+                    failed_members.push(format!("'{}'", member.name));
+                }
+                (
+                    String::from_utf8_lossy(&output.stdout).into_owned(),
+                    String::from_utf8_lossy(&output.stderr).into_owned(),
+                )
+            }
+            Err(error) => {
+                failed_members.push(format!("'{}'", member.name));
+                (String::new(), format!("Could not run `yard`: {error}\n"))
+            }
+        };
+        if !stderr.is_empty() {
+            eprintln!("Member '{}':\n{}", member.name, stderr.trim_end());
+        }
+        stdouts.push((member, stdout));
+    }
+    if !failed_members.is_empty() {
+        for (_, stdout) in &stdouts {
+            print!("{stdout}");
+        }
+        bail!(
+            "{} of {} workspace members failed: {}.",
+            failed_members.len(),
+            members.len(),
+            failed_members.join(", ")
+        );
+    }
+    Ok(stdouts)
+}
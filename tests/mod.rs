@@ -1,6 +1,6 @@
 use std::fs;
 
-use predicates::prelude::predicate;
+use predicates::prelude::{PredicateBooleanExt, predicate};
 
 #[test]
 fn conflicting_required_files() {
@@ -57,6 +57,18 @@ fn output_order() {
     ));
 }
 
+#[test]
+fn matrix_output_order() {
+    let assert = assert_cmd::Command::cargo_bin("yard")
+        .unwrap()
+        .current_dir("tests/matrix_outputs")
+        .arg("outputs")
+        .assert();
+    assert.success().stdout(predicate::eq(
+        "out.22.04-12.2.Containerfile\nout.22.04-12.4.Containerfile\nout.24.04-12.2.Containerfile\nout.24.04-12.4.Containerfile\n",
+    ));
+}
+
 #[test]
 fn independent_modules() {
     let assert = assert_cmd::Command::cargo_bin("yard")
@@ -134,3 +146,1146 @@ fn duplicate_module_rejected() {
         }
     }
 }
+
+#[test]
+fn independent_module_stages() {
+    let assert = assert_cmd::Command::cargo_bin("yard")
+        .unwrap()
+        .current_dir("tests/independent_module_stages")
+        .arg("build")
+        .assert();
+    assert.success();
+    let output = fs::read_to_string("tests/independent_module_stages/out.Containerfile").unwrap();
+
+    // Each independent module instance gets its own stage name, used by both stages.
+    assert!(output.contains("FROM python:3.11-slim AS yard-python_deps"));
+    assert!(output.contains("COPY --from=yard-python_deps /opt/venv /opt/venv"));
+    assert!(output.contains("FROM node:20-slim AS yard-node_deps"));
+    assert!(output.contains("COPY --from=yard-node_deps /opt/node /opt/node"));
+}
+
+#[test]
+fn duplicate_stage_names_rejected() {
+    let assert = assert_cmd::Command::cargo_bin("yard")
+        .unwrap()
+        .current_dir("tests/duplicate_stage_names")
+        .arg("build")
+        .assert();
+    assert
+        .failure()
+        .stderr(predicate::str::contains(
+            "Build stage name 'builder' is declared more than once",
+        ))
+        .stderr(predicate::str::contains("Local path: one.md"))
+        .stderr(predicate::str::contains("Local path: two.md"));
+    assert!(!std::path::Path::new("tests/duplicate_stage_names/out.Containerfile").exists());
+}
+
+#[test]
+fn multi_stage_module() {
+    let assert = assert_cmd::Command::cargo_bin("yard")
+        .unwrap()
+        .current_dir("tests/multi_stage_module")
+        .arg("build")
+        .assert();
+    assert.success();
+    let output = fs::read_to_string("tests/multi_stage_module/out.Containerfile").unwrap();
+
+    let find = |needle: &str| {
+        output
+            .find(needle)
+            .unwrap_or_else(|| panic!("'{needle}' should be present"))
+    };
+    // Stages are hoisted in declaration order, the install part is injected where the module is
+    // declared and the finalizer is appended to the end.
+    let fetch_idx = find("FROM alpine:latest AS yard-tool-fetch");
+    let compile_idx = find("FROM alpine:latest AS yard-tool-compile");
+    let inline_from_idx = find("FROM alpine:latest\n");
+    let install_idx = find("COPY --from=yard-tool-compile /tool /usr/local/bin/tool");
+    let echo_idx = find("RUN echo after");
+    let final_idx = find("ENTRYPOINT [\"/usr/local/bin/tool\"]");
+    assert!(fetch_idx < compile_idx);
+    assert!(compile_idx < inline_from_idx);
+    assert!(inline_from_idx < install_idx);
+    assert!(install_idx < echo_idx);
+    assert!(echo_idx < final_idx);
+    assert!(output.contains("COPY --from=yard-tool-fetch /src /src"));
+}
+
+#[test]
+fn markdown_module_file() {
+    let assert = assert_cmd::Command::cargo_bin("yard")
+        .unwrap()
+        .current_dir("tests/markdown_module_file")
+        .arg("build")
+        .assert();
+    assert
+        .success()
+        .stderr(predicate::str::contains(
+            "Warning: module.md:12:1: Ignoring config block, the config is already declared at module.md:5:1.",
+        ))
+        .stderr(predicate::str::contains(
+            "Warning: module.md:16:1: Ignoring attribute 'title=\"Base image\"', it is not a block role.",
+        ))
+        .stderr(predicate::str::contains(
+            "Ignoring attribute '{.numberLines}', it is not a block role.",
+        ));
+    let output = fs::read_to_string("tests/markdown_module_file/Containerfile").unwrap();
+    assert!(output.contains("FROM alpine:latest\n```bash"));
+    assert!(output.contains("RUN echo hello"));
+    // The attribute does not change the role of the block
+    assert!(output.contains("(final)  ####\n\nRUN echo hello"));
+    assert!(!output.contains("Documentation blocks are ignored"));
+}
+
+#[test]
+fn unclosed_fence_rejected() {
+    let assert = assert_cmd::Command::cargo_bin("yard")
+        .unwrap()
+        .current_dir("tests/unclosed_fence")
+        .arg("build")
+        .assert();
+    assert.failure().stderr(predicate::str::contains(
+        "module.md:5:1: Code fence is never closed.",
+    ));
+}
+
+#[test]
+fn directory_module() {
+    let assert = assert_cmd::Command::cargo_bin("yard")
+        .unwrap()
+        .current_dir("tests/directory_module")
+        .arg("build")
+        .assert();
+    assert.success();
+    let output = fs::read_to_string("tests/directory_module/out.Containerfile").unwrap();
+
+    let build_stage_idx = output
+        .find("FROM python:3.11-slim AS yard-python_deps")
+        .expect("build stage from `Containerfile` should be present");
+    assert!(output.contains("RUN /opt/venv/bin/pip install --no-cache-dir numpy"));
+    let install_stage_idx = output
+        .find("COPY --from=yard-python_deps /opt/venv /opt/venv")
+        .expect("install stage from `install.Containerfile` should be present");
+    assert!(build_stage_idx < install_stage_idx);
+}
+
+#[test]
+fn invalid_module_blocks_rejected() {
+    let yard = |file: &str| {
+        assert_cmd::Command::cargo_bin("yard")
+            .unwrap()
+            .current_dir("tests/invalid_module_blocks")
+            .args(["build", "-f", file])
+            .assert()
+    };
+    yard("not_independent.yard.yaml")
+        .failure()
+        .stderr(predicate::str::contains(
+            "python_deps/install.Containerfile:1: Found an install stage, but the module is not independent. Add `independent: true` to 'python_deps/module.yaml'",
+        ));
+    yard("extra_blocks.yard.yaml")
+        .failure()
+        .stderr(predicate::str::contains(
+            "tool.md:10: Found more containerfile/dockerfile blocks than expected.",
+        ));
+    assert!(!fs::exists("tests/invalid_module_blocks/out.Containerfile").unwrap());
+}
+
+#[test]
+fn template_libraries() {
+    let assert = assert_cmd::Command::cargo_bin("yard")
+        .unwrap()
+        .current_dir("tests/template_libraries")
+        .arg("build")
+        .assert();
+    assert.success();
+    let output = fs::read_to_string("tests/template_libraries/Containerfile").unwrap();
+    // Rendered from the `apt.install` component
+    assert!(output.contains("apt-get install -y --no-install-recommends git curl"));
+    // Included
+    assert!(output.contains("LABEL maintainer=\"yard\""));
+}
+
+#[test]
+fn yard_template_object() {
+    let assert = assert_cmd::Command::cargo_bin("yard")
+        .unwrap()
+        .current_dir("tests/yard_template_object")
+        .arg("build")
+        .assert();
+    assert.success();
+    let output = fs::read_to_string("tests/yard_template_object/Containerfile").unwrap();
+    assert!(
+        output.contains(r#"LABEL output="Containerfile" module="labels" source="local:labels.md""#)
+    );
+    assert!(output.contains(r#"LABEL modules="inline_0,labels""#));
+}
+
+#[test]
+fn global_vars() {
+    let evaluations_log = "tests/global_vars/evaluations.log";
+    let _ = fs::remove_file(evaluations_log);
+    let assert = assert_cmd::Command::cargo_bin("yard")
+        .unwrap()
+        .current_dir("tests/global_vars")
+        .arg("build")
+        .assert();
+    assert.success();
+    let one = fs::read_to_string("tests/global_vars/out.one.Containerfile").unwrap();
+    assert!(one.contains("RUN echo hello world"));
+    let two = fs::read_to_string("tests/global_vars/out.two.Containerfile").unwrap();
+    assert!(two.contains("RUN echo goodbye world"));
+    assert!(two.contains("RUN docker inspect --format '{{.State.Status}} of world' app"));
+    // The var is only evaluated once per build
+    assert_eq!(fs::read_to_string(evaluations_log).unwrap(), "run\n");
+    fs::remove_file(evaluations_log).unwrap();
+}
+
+#[test]
+fn inline_templating() {
+    let assert = assert_cmd::Command::cargo_bin("yard")
+        .unwrap()
+        .current_dir("tests/inline_templating")
+        .env("YARD_TEST_GREETING", "hello")
+        .arg("build")
+        .assert();
+    assert.success();
+    let containerfile = fs::read_to_string("tests/inline_templating/out.Containerfile").unwrap();
+    assert!(containerfile.contains("FROM ubuntu"));
+    assert!(containerfile.contains("RUN echo hello"));
+    assert!(containerfile.contains("RUN apt install git ubuntu-keyring"));
+}
+
+#[test]
+fn output_extends() {
+    let assert = assert_cmd::Command::cargo_bin("yard")
+        .unwrap()
+        .current_dir("tests/output_extends")
+        .arg("build")
+        .assert();
+    assert.success();
+    let base = fs::read_to_string("tests/output_extends/out.base.Containerfile").unwrap();
+    let middle = fs::read_to_string("tests/output_extends/out.middle.Containerfile").unwrap();
+    let extended = fs::read_to_string("tests/output_extends/out.extended.Containerfile").unwrap();
+    let lines = |containerfile: &str| -> Vec<String> {
+        containerfile
+            .lines()
+            .filter(|line| line.starts_with("RUN") || line.starts_with("FROM"))
+            .map(str::to_owned)
+            .collect()
+    };
+    assert_eq!(
+        lines(&base),
+        ["RUN echo base", "RUN echo tools", "RUN echo finalizer"]
+    );
+    assert_eq!(
+        lines(&middle),
+        [
+            "FROM alpine",
+            "RUN echo tools",
+            "RUN echo extra",
+            "RUN echo finalizer"
+        ]
+    );
+    assert_eq!(
+        lines(&extended),
+        [
+            "FROM alpine",
+            "RUN echo extra",
+            "RUN echo before finalizer",
+            "RUN echo finalizer"
+        ]
+    );
+}
+
+#[test]
+fn output_extends_cycle_rejected() {
+    let assert = assert_cmd::Command::cargo_bin("yard")
+        .unwrap()
+        .current_dir("tests/output_extends_cycle")
+        .arg("build")
+        .assert();
+    assert.failure().stderr(predicate::str::contains(
+        "out.one.Containerfile -> out.two.Containerfile -> out.one.Containerfile",
+    ));
+}
+
+#[test]
+fn matrix_outputs() {
+    let assert = assert_cmd::Command::cargo_bin("yard")
+        .unwrap()
+        .current_dir("tests/matrix_outputs")
+        .arg("build")
+        .assert();
+    assert.success();
+    for (ubuntu, cuda) in [
+        ("22.04", "12.2"),
+        ("22.04", "12.4"),
+        ("24.04", "12.2"),
+        ("24.04", "12.4"),
+    ] {
+        let containerfile = fs::read_to_string(format!(
+            "tests/matrix_outputs/out.{ubuntu}-{cuda}.Containerfile"
+        ))
+        .unwrap();
+        assert!(containerfile.contains(&format!("FROM nvidia/cuda:{cuda}-ubuntu{ubuntu}")));
+    }
+}
+
+#[test]
+fn conditional_modules() {
+    let assert = assert_cmd::Command::cargo_bin("yard")
+        .unwrap()
+        .current_dir("tests/conditional_modules")
+        .env("YARD_TEST_EXTRA", "1")
+        .arg("build")
+        .assert();
+    assert.success();
+    let ci = fs::read_to_string("tests/conditional_modules/out.ci.Containerfile").unwrap();
+    assert!(!ci.contains("RUN apt install gdb"));
+    assert!(ci.contains("RUN echo extra"));
+    let dev = fs::read_to_string("tests/conditional_modules/out.dev.Containerfile").unwrap();
+    assert!(dev.contains("RUN apt install gdb"));
+}
+
+#[test]
+fn invalid_when_rejected() {
+    let yard = |file: &str| {
+        assert_cmd::Command::cargo_bin("yard")
+            .unwrap()
+            .current_dir("tests/invalid_when")
+            .args(["build", "-f", file])
+            .assert()
+    };
+    yard("injection.yard.yaml")
+        .failure()
+        .stderr(predicate::str::contains(
+            "A condition is a single expression, without `{{`, `{%` or `{#` delimiters.",
+        ))
+        .stderr(predicate::str::contains(
+            "of the module 'app' in the output 'out.Containerfile'.",
+        ));
+    // Errors show the condition as written, not the template it is evaluated in
+    yard("typo.yard.yaml")
+        .failure()
+        .stderr(predicate::str::contains(
+            "Could not evaluate `when: profile == = \"ci\"` of the module 'app' in the output 'out.Containerfile'.",
+        ))
+        .stderr(predicate::str::contains("{% if").not());
+    assert!(!fs::exists("tests/invalid_when/out.Containerfile").unwrap());
+}
+
+#[test]
+fn explain_skipped_modules() {
+    let assert = assert_cmd::Command::cargo_bin("yard")
+        .unwrap()
+        .current_dir("tests/conditional_modules")
+        .env_remove("YARD_TEST_EXTRA")
+        .arg("explain")
+        .assert();
+    assert.success().stdout(predicate::eq(
+        r#"Skipped module 'dev_tools' in 'out.ci.Containerfile', `when: flavor == "dev"` is false.
+Skipped module 'missing' in 'out.ci.Containerfile', `when: vars.include_missing == "yes"` is false.
+Skipped inline module `RUN echo extra` in 'out.ci.Containerfile', `when: env.YARD_TEST_EXTRA == "1"` is false.
+Skipped module 'missing' in 'out.dev.Containerfile', `when: vars.include_missing == "yes"` is false.
+Skipped inline module `RUN echo extra` in 'out.dev.Containerfile', `when: env.YARD_TEST_EXTRA == "1"` is false.
+"#,
+    ));
+}
+
+#[test]
+fn profiles() {
+    let _ = fs::remove_file("tests/profiles/out.dev.Containerfile");
+    let assert = assert_cmd::Command::cargo_bin("yard")
+        .unwrap()
+        .current_dir("tests/profiles")
+        .args(["build", "--profile", "ci"])
+        .assert();
+    assert
+        .success()
+        .stdout(predicate::str::contains("ci post hook"));
+    let containerfile = fs::read_to_string("tests/profiles/out.ci.Containerfile").unwrap();
+    assert!(containerfile.starts_with("# Profile: ci\n"));
+    assert!(containerfile.contains("RUN echo ci via http://proxy:3128"));
+    assert!(containerfile.contains("RUN echo ci only"));
+    // Not selected by the profile
+    assert!(!fs::exists("tests/profiles/out.dev.Containerfile").unwrap());
+}
+
+#[test]
+fn profile_from_env() {
+    let assert = assert_cmd::Command::cargo_bin("yard")
+        .unwrap()
+        .current_dir("tests/profiles")
+        .env("YARD_PROFILE", "ci")
+        .arg("outputs")
+        .assert();
+    assert
+        .success()
+        .stdout(predicate::eq("out.ci.Containerfile\n"));
+}
+
+#[test]
+fn unknown_profile_rejected() {
+    let assert = assert_cmd::Command::cargo_bin("yard")
+        .unwrap()
+        .current_dir("tests/profiles")
+        .args(["outputs", "--profile", "release"])
+        .assert();
+    assert.failure().stderr(predicate::str::contains(
+        "Profile 'release' is not declared",
+    ));
+}
+
+#[test]
+fn yard_include() {
+    let assert = assert_cmd::Command::cargo_bin("yard")
+        .unwrap()
+        .current_dir("tests/yard_include")
+        .arg("build")
+        .assert();
+    assert.success();
+    let base = fs::read_to_string("tests/yard_include/out.base.Containerfile").unwrap();
+    assert!(base.contains("FROM registry.company.com/ubuntu"));
+    assert!(base.contains("RUN update-ca-certificates"));
+    let app = fs::read_to_string("tests/yard_include/out.app.Containerfile").unwrap();
+    assert!(app.contains("ENV http_proxy=http://project-proxy:3128"));
+    assert!(app.contains("RUN echo app"));
+}
+
+#[test]
+fn yard_include_conflict_rejected() {
+    let assert = assert_cmd::Command::cargo_bin("yard")
+        .unwrap()
+        .current_dir("tests/yard_include_conflict")
+        .arg("build")
+        .assert();
+    assert
+        .failure()
+        .stderr(predicate::str::contains("Could not include 'two.yaml'"))
+        .stderr(predicate::str::contains(
+            "'vars.registry' is declared with different values by more than one include.",
+        ));
+}
+
+#[test]
+fn yard_include_from_sibling_directory() {
+    let dir = "tests/yard_include_sibling/project";
+    let build_dir = format!("{dir}/build");
+    let _ = fs::remove_dir_all(&build_dir);
+    assert_cmd::Command::cargo_bin("yard")
+        .unwrap()
+        .current_dir(dir)
+        .arg("build")
+        .assert()
+        .success();
+    let containerfile = fs::read_to_string(format!("{build_dir}/out.Containerfile")).unwrap();
+    assert!(containerfile.contains("####  ca: ../company/ca.md  ####"));
+    assert!(containerfile.contains("RUN update-ca-certificates"));
+    // The required file of the included module is relative to the included file
+    assert_eq!(
+        fs::read_to_string(format!("{build_dir}/ca.crt")).unwrap(),
+        "company certificate\n"
+    );
+    fs::remove_dir_all(build_dir).unwrap();
+}
+
+#[test]
+fn remote_include_local_modules_are_remote() {
+    let root = tempfile::tempdir().unwrap();
+    let root = root.path();
+    let repo = root.join("repo");
+    fs::create_dir_all(repo.join("company")).unwrap();
+    fs::write(
+        repo.join("company/base.yaml"),
+        "inputs:\n  modules:\n    ca: ca.md\n",
+    )
+    .unwrap();
+    fs::write(
+        repo.join("company/ca.md"),
+        "```yaml\nrequired_files:\n  - ca.crt\n```\n```dockerfile\nCOPY ca.crt /ca.crt\n```\n",
+    )
+    .unwrap();
+    fs::write(repo.join("company/ca.crt"), "company certificate\n").unwrap();
+    let git = |args: &[&str]| {
+        std::process::Command::new("git")
+            .args(["-c", "user.name=yard", "-c", "user.email=yard@example.com"])
+            .args(args)
+            .current_dir(&repo)
+            .output()
+            .unwrap()
+    };
+    assert!(git(&["init", "--quiet"]).status.success());
+    assert!(git(&["add", "."]).status.success());
+    assert!(git(&["commit", "--quiet", "-m", "base"]).status.success());
+    let commit = String::from_utf8(git(&["rev-parse", "HEAD"]).stdout).unwrap();
+    let project = root.join("project");
+    fs::create_dir(&project).unwrap();
+    fs::write(
+        project.join("yard.yaml"),
+        format!(
+            "include:\n  - url: https://github.com/yard-test/base\n    commit: {}\n    path: company/base.yaml\noutputs:\n  out.Containerfile:\n    - FROM alpine:3.20\n    - ca:\n",
+            commit.trim()
+        ),
+    )
+    .unwrap();
+    assert_cmd::Command::cargo_bin("yard")
+        .unwrap()
+        .current_dir(&project)
+        .env("XDG_CACHE_HOME", root.join("cache"))
+        .env("GIT_CONFIG_COUNT", "1")
+        .env(
+            "GIT_CONFIG_KEY_0",
+            format!("url.file://{}.insteadOf", repo.display()),
+        )
+        .env("GIT_CONFIG_VALUE_0", "https://github.com/yard-test/base")
+        .arg("build")
+        .timeout(std::time::Duration::from_secs(60))
+        .assert()
+        .success();
+    let containerfile = fs::read_to_string(project.join("out.Containerfile")).unwrap();
+    assert!(containerfile.contains("####  ca: company/ca.md  ####"));
+    assert_eq!(
+        fs::read_to_string(project.join("ca.crt")).unwrap(),
+        "company certificate\n"
+    );
+}
+
+#[test]
+fn workspace() {
+    let assert = assert_cmd::Command::cargo_bin("yard")
+        .unwrap()
+        .current_dir("tests/workspace")
+        .args(["build", "--workspace"])
+        .assert();
+    assert.success();
+    for (member, module) in [
+        ("services/api", "api"),
+        ("services/web", "web"),
+        ("tools", "tools"),
+    ] {
+        let containerfile =
+            fs::read_to_string(format!("tests/workspace/{member}/out.Containerfile")).unwrap();
+        assert!(containerfile.contains(&format!("RUN echo {module}")));
+    }
+
+    let assert = assert_cmd::Command::cargo_bin("yard")
+        .unwrap()
+        .current_dir("tests/workspace")
+        .args(["outputs", "--workspace"])
+        .assert();
+    assert.success().stdout(predicate::eq(
+        "services/api/out.Containerfile\nservices/web/out.Containerfile\ntools/out.Containerfile\n",
+    ));
+}
+
+#[test]
+fn workspace_members_pin_one_repo_at_different_commits() {
+    let root = tempfile::tempdir().unwrap();
+    let root = root.path();
+    let git = |args: &[&str]| {
+        let status = std::process::Command::new("git")
+            .args(["-c", "user.name=yard", "-c", "user.email=yard@example.com"])
+            .args(args)
+            .current_dir(root.join("repo"))
+            .status()
+            .unwrap();
+        assert!(status.success());
+    };
+    let commit = || {
+        let output = std::process::Command::new("git")
+            .args(["rev-parse", "HEAD"])
+            .current_dir(root.join("repo"))
+            .output()
+            .unwrap();
+        String::from_utf8(output.stdout).unwrap().trim().to_owned()
+    };
+    fs::create_dir(root.join("repo")).unwrap();
+    git(&["init", "--quiet"]);
+    let mut commits = Vec::new();
+    for version in ["one", "two"] {
+        fs::write(
+            root.join("repo/module.md"),
+            format!("```dockerfile\nRUN echo {version}\n```\n"),
+        )
+        .unwrap();
+        git(&["add", "module.md"]);
+        git(&["commit", "--quiet", "-m", version]);
+        commits.push(commit());
+    }
+    let workspace = root.join("workspace");
+    fs::create_dir(&workspace).unwrap();
+    fs::write(
+        workspace.join("yard-workspace.yaml"),
+        "members:\n  - one\n  - two\n",
+    )
+    .unwrap();
+    for (member, commit) in ["one", "two"].iter().zip(&commits) {
+        fs::create_dir(workspace.join(member)).unwrap();
+        fs::write(
+            workspace.join(member).join("yard.yaml"),
+            format!(
+                "inputs:\n  remotes:\n    - url: https://github.com/yard-test/shared\n      commit: {commit}\n      modules:\n        shared: module.md\noutputs:\n  out.Containerfile:\n    - FROM alpine:3.20\n    - shared:\n"
+            ),
+        )
+        .unwrap();
+    }
+    // The remote is served from the local repo, and cloned into a cache of its own
+    assert_cmd::Command::cargo_bin("yard")
+        .unwrap()
+        .current_dir(&workspace)
+        .env("XDG_CACHE_HOME", root.join("cache"))
+        .env("GIT_CONFIG_COUNT", "1")
+        .env(
+            "GIT_CONFIG_KEY_0",
+            format!("url.file://{}.insteadOf", root.join("repo").display()),
+        )
+        .env("GIT_CONFIG_VALUE_0", "https://github.com/yard-test/shared")
+        .args(["build", "--workspace"])
+        .timeout(std::time::Duration::from_secs(60))
+        .assert()
+        .success();
+    for member in ["one", "two"] {
+        let containerfile =
+            fs::read_to_string(workspace.join(member).join("out.Containerfile")).unwrap();
+        assert!(containerfile.contains(&format!("RUN echo {member}")));
+    }
+}
+
+#[test]
+fn workspace_failure_names_member() {
+    let _ = fs::remove_file("tests/workspace_failure/ok/out.Containerfile");
+    let assert = assert_cmd::Command::cargo_bin("yard")
+        .unwrap()
+        .current_dir("tests/workspace_failure")
+        .args(["build", "--workspace"])
+        .assert();
+    assert
+        .failure()
+        .stderr(predicate::str::contains(
+            "1 of 2 workspace members failed: 'broken'.",
+        ))
+        .stderr(predicate::str::contains("Member 'broken':"))
+        .stderr(predicate::str::contains("missing.md"))
+        // The output of members that succeeded is kept
+        .stdout(predicate::str::contains("Created 'out.Containerfile'"));
+    // Other members are still built
+    assert!(fs::exists("tests/workspace_failure/ok/out.Containerfile").unwrap());
+}
+
+#[test]
+fn yard_file_option_and_output_dir() {
+    let _ = fs::remove_dir_all("tests/yard_file_option/build");
+    let _ = fs::remove_dir_all("tests/yard_file_option/out_dir");
+    let assert = assert_cmd::Command::cargo_bin("yard")
+        .unwrap()
+        .current_dir("tests/yard_file_option")
+        .args(["build", "-f", "ci.yard.yaml"])
+        .assert();
+    assert.success();
+    let containerfile =
+        fs::read_to_string("tests/yard_file_option/build/out.ci.Containerfile").unwrap();
+    assert!(containerfile.contains("COPY files/app.conf /etc/app.conf"));
+    // Required files are placed in the build context
+    let app_conf = fs::read_to_string("tests/yard_file_option/build/files/app.conf").unwrap();
+    assert_eq!(app_conf, "port = 8080\n");
+    assert!(!fs::exists("tests/yard_file_option/out.ci.Containerfile").unwrap());
+
+    let assert = assert_cmd::Command::cargo_bin("yard")
+        .unwrap()
+        .current_dir("tests/yard_file_option")
+        .args(["build", "-f", "ci.yard.yaml", "--out-dir", "out_dir"])
+        .assert();
+    assert.success();
+    assert!(fs::exists("tests/yard_file_option/out_dir/out.ci.Containerfile").unwrap());
+    assert!(fs::exists("tests/yard_file_option/out_dir/files/app.conf").unwrap());
+
+    let assert = assert_cmd::Command::cargo_bin("yard")
+        .unwrap()
+        .current_dir("tests/yard_file_option")
+        .args(["outputs", "--file", "dev.yard.yaml"])
+        .assert();
+    assert
+        .success()
+        .stdout(predicate::eq("out.dev.Containerfile\n"));
+
+    fs::remove_dir_all("tests/yard_file_option/build").unwrap();
+    fs::remove_dir_all("tests/yard_file_option/out_dir").unwrap();
+}
+
+#[test]
+fn output_contexts() {
+    let _ = fs::remove_dir_all("tests/output_contexts/build");
+    let assert = assert_cmd::Command::cargo_bin("yard")
+        .unwrap()
+        .current_dir("tests/output_contexts")
+        .arg("build")
+        .assert();
+    assert.success();
+    let build_dir = "tests/output_contexts/build";
+    // Each context only receives the required files of its own modules
+    for (context, other) in [("api", "web"), ("web", "api")] {
+        let containerfile =
+            fs::read_to_string(format!("{build_dir}/{context}/out.{context}.Containerfile"))
+                .unwrap();
+        assert!(
+            containerfile.contains(&format!("COPY {context}.conf shared.conf /etc/{context}/"))
+        );
+        assert!(fs::exists(format!("{build_dir}/{context}/{context}.conf")).unwrap());
+        assert!(fs::exists(format!("{build_dir}/{context}/shared.conf")).unwrap());
+        assert!(!fs::exists(format!("{build_dir}/{context}/{other}.conf")).unwrap());
+    }
+    assert!(fs::exists(format!("{build_dir}/out.Containerfile")).unwrap());
+    assert!(!fs::exists(format!("{build_dir}/shared.conf")).unwrap());
+
+    fs::remove_dir_all(build_dir).unwrap();
+}
+
+#[test]
+fn build_check() {
+    let build_dir = "tests/build_check/build";
+    let pre_hook_ran = "tests/build_check/pre_hook_ran";
+    let _ = fs::remove_dir_all(build_dir);
+    let _ = fs::remove_file(pre_hook_ran);
+    let check = || {
+        assert_cmd::Command::cargo_bin("yard")
+            .unwrap()
+            .current_dir("tests/build_check")
+            .args(["build", "--check"])
+            .assert()
+    };
+    check()
+        .failure()
+        .stdout(predicate::str::contains("--- /dev/null"))
+        .stdout(predicate::str::contains("+++ b/build/out.Containerfile"))
+        .stdout(predicate::str::contains("+COPY app.conf /etc/app.conf"))
+        .stdout(predicate::str::contains("+++ b/build/app.conf"))
+        .stderr(predicate::str::contains(
+            "2 of 2 generated files are out of date.",
+        ));
+    // Nothing is written, and the pre-build hook, which may write files, is not run
+    assert!(!fs::exists(build_dir).unwrap());
+    assert!(!fs::exists(pre_hook_ran).unwrap());
+
+    let assert = assert_cmd::Command::cargo_bin("yard")
+        .unwrap()
+        .current_dir("tests/build_check")
+        .arg("build")
+        .assert();
+    assert.success();
+    check().success().stdout(predicate::str::contains(
+        "All generated files are up to date.",
+    ));
+
+    fs::write(format!("{build_dir}/app.conf"), "port = 80\n").unwrap();
+    check()
+        .failure()
+        .stdout(predicate::str::contains("--- a/build/app.conf"))
+        .stdout(predicate::str::contains("-port = 80"))
+        .stdout(predicate::str::contains("+port = 8080"))
+        .stderr(predicate::str::contains(
+            "1 of 2 generated files are out of date.",
+        ));
+    assert_eq!(
+        fs::read_to_string(format!("{build_dir}/app.conf")).unwrap(),
+        "port = 80\n"
+    );
+
+    fs::remove_dir_all(build_dir).unwrap();
+    fs::remove_file(pre_hook_ran).unwrap();
+}
+
+#[test]
+fn yard_diff() {
+    let _ = fs::remove_file("tests/yard_diff/out.new.Containerfile");
+    let hand_tweaked = "####  ~INLINE~  ####\n\nFROM alpine:3.19\n";
+    fs::write("tests/yard_diff/out.app.Containerfile", hand_tweaked).unwrap();
+    fs::write(
+        "tests/yard_diff/out.old.Containerfile",
+        "####  ~INLINE~  ####\n\nFROM alpine:3.18\n",
+    )
+    .unwrap();
+    let assert = assert_cmd::Command::cargo_bin("yard")
+        .unwrap()
+        .current_dir("tests/yard_diff")
+        .arg("diff")
+        .assert();
+    assert
+        .success()
+        .stdout(predicate::str::contains("--- a/out.app.Containerfile"))
+        .stdout(predicate::str::contains("-FROM alpine:3.19"))
+        .stdout(predicate::str::contains("+FROM alpine:3.20"))
+        .stdout(predicate::str::contains("New file 'out.new.Containerfile'"))
+        .stdout(predicate::str::contains(
+            "Not produced anymore 'out.old.Containerfile'",
+        ))
+        .stdout(predicate::str::contains(
+            "1 new, 1 modified, 1 no longer produced.",
+        ));
+    // Nothing is written
+    assert_eq!(
+        fs::read_to_string("tests/yard_diff/out.app.Containerfile").unwrap(),
+        hand_tweaked
+    );
+    assert!(!fs::exists("tests/yard_diff/out.new.Containerfile").unwrap());
+
+    assert_cmd::Command::cargo_bin("yard")
+        .unwrap()
+        .current_dir("tests/yard_diff")
+        .args(["explain", ".", "out.app.Containerfile"])
+        .assert()
+        .success();
+    assert!(!fs::exists("tests/yard_diff/pre_hook_ran").unwrap());
+}
+
+#[test]
+fn failed_build_changes_nothing() {
+    let build_dir = "tests/atomic_writes/build";
+    let _ = fs::remove_dir_all(build_dir);
+    fs::create_dir_all(build_dir).unwrap();
+    fs::write(format!("{build_dir}/out.a.Containerfile"), "old").unwrap();
+    // The context of `out.b.Containerfile` can not be created, after `out.a.Containerfile` is moved
+    // into place
+    fs::write(format!("{build_dir}/blocked"), "").unwrap();
+    let assert = assert_cmd::Command::cargo_bin("yard")
+        .unwrap()
+        .current_dir("tests/atomic_writes")
+        .arg("build")
+        .assert();
+    assert
+        .failure()
+        .stderr(predicate::str::contains("No files were changed."));
+    assert_eq!(
+        fs::read_to_string(format!("{build_dir}/out.a.Containerfile")).unwrap(),
+        "old"
+    );
+    let mut entries: Vec<String> = fs::read_dir(build_dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+        .collect();
+    entries.sort();
+    assert_eq!(entries, ["blocked", "out.a.Containerfile"]);
+
+    fs::remove_dir_all(build_dir).unwrap();
+}
+
+#[test]
+fn yard_clean() {
+    let dir = "tests/yard_clean";
+    let yard = |args: &[&str]| {
+        assert_cmd::Command::cargo_bin("yard")
+            .unwrap()
+            .current_dir(dir)
+            .args(args)
+            .assert()
+    };
+    yard(&["build"]).success();
+    for file in [
+        "out.a.Containerfile",
+        "ctx/out.b.Containerfile",
+        "ctx/app.conf",
+    ] {
+        assert!(fs::exists(format!("{dir}/{file}")).unwrap());
+    }
+    let generated = fs::read_to_string(format!("{dir}/.yard-generated")).unwrap();
+    assert!(generated.contains("ctx/app.conf\n"));
+    assert!(!generated.contains("\napp.conf\n"));
+
+    // Outputs the profile leaves out, and their required files, are kept
+    yard(&["build", "--profile", "minimal"])
+        .success()
+        .stdout(predicate::str::contains("no longer generated").not());
+    assert!(fs::exists(format!("{dir}/ctx/out.b.Containerfile")).unwrap());
+    assert!(fs::exists(format!("{dir}/ctx/app.conf")).unwrap());
+    let generated = fs::read_to_string(format!("{dir}/.yard-generated")).unwrap();
+    assert!(generated.contains("ctx/app.conf\n"));
+
+    // Files the current configuration no longer produces are removed
+    yard(&["build", "--profile", "no_app"])
+        .success()
+        .stdout(predicate::str::contains(
+            "ctx/app.conf', it is no longer generated",
+        ));
+    assert!(fs::exists(format!("{dir}/ctx/out.b.Containerfile")).unwrap());
+    assert!(!fs::exists(format!("{dir}/ctx/app.conf")).unwrap());
+
+    yard(&["build"]).success();
+    yard(&["clean"]).success();
+    let mut entries: Vec<String> = fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+        .collect();
+    entries.sort();
+    // Sources are kept, including the local required file
+    assert_eq!(entries, ["app.conf", "app.md", "yard.yaml"]);
+
+    yard(&["clean"])
+        .success()
+        .stdout(predicate::str::contains("Nothing to clean"));
+}
+
+#[test]
+fn yard_files_share_output_dir() {
+    let dir = "tests/yard_file_option";
+    let yard = |args: &[&str]| {
+        assert_cmd::Command::cargo_bin("yard")
+            .unwrap()
+            .current_dir(dir)
+            .args(args)
+            .assert()
+    };
+    yard(&["build", "-f", "dev.yard.yaml", "--out-dir", "shared"]).success();
+    yard(&["build", "-f", "ci.yard.yaml", "--out-dir", "shared"])
+        .success()
+        .stdout(predicate::str::contains("no longer generated").not());
+    // Each yard file records its own files, and leaves the ones of the other in place
+    for file in [
+        "out.dev.Containerfile",
+        "out.ci.Containerfile",
+        "files/app.conf",
+    ] {
+        assert!(fs::exists(format!("{dir}/shared/{file}")).unwrap());
+    }
+    let generated = fs::read_to_string(format!("{dir}/shared/.yard-generated.dev.yard")).unwrap();
+    assert!(generated.contains("out.dev.Containerfile\n"));
+    assert!(!generated.contains("out.ci.Containerfile\n"));
+    yard(&["diff", "-f", "dev.yard.yaml", "--out-dir", "shared"])
+        .success()
+        .stdout(predicate::eq("No changes.\n"));
+
+    // Cleaning one yard file keeps the files the other one generates too
+    yard(&["clean", "-f", "dev.yard.yaml", "--out-dir", "shared"]).success();
+    assert!(!fs::exists(format!("{dir}/shared/out.dev.Containerfile")).unwrap());
+    assert!(fs::exists(format!("{dir}/shared/out.ci.Containerfile")).unwrap());
+    assert!(fs::exists(format!("{dir}/shared/files/app.conf")).unwrap());
+    fs::remove_dir_all(format!("{dir}/shared")).unwrap();
+}
+
+#[test]
+fn provenance() {
+    let dir = "tests/provenance";
+    let build_dir = format!("{dir}/build");
+    let _ = fs::remove_dir_all(&build_dir);
+    assert_cmd::Command::cargo_bin("yard")
+        .unwrap()
+        .current_dir(dir)
+        .arg("build")
+        .assert()
+        .success();
+    let output = fs::read_to_string(format!("{build_dir}/out.Containerfile")).unwrap();
+    assert!(output.starts_with(&format!(
+        "# Generated by yard {}\n",
+        env!("CARGO_PKG_VERSION")
+    )));
+    assert!(output.contains(
+        "#   deps: deps.md\n#     api_token=<redacted>\n#     auth_token=<redacted>\n#     author=Jane Doe\n#     version=1.2\n"
+    ));
+    assert!(
+        !output
+            .lines()
+            .any(|line| line.starts_with('#') && line.contains("hunter2"))
+    );
+
+    let source_map: serde_json::Value = serde_json::from_str(
+        &fs::read_to_string(format!("{build_dir}/out.Containerfile.yardmap")).unwrap(),
+    )
+    .unwrap();
+    assert_eq!(source_map["version"], 1);
+    let lines: Vec<&str> = output.lines().collect();
+    let parts = source_map["parts"].as_array().unwrap();
+    let roles: Vec<Option<&str>> = parts.iter().map(|part| part["role"].as_str()).collect();
+    assert_eq!(roles, [Some("build stage"), None, Some("install stage")]);
+    for part in parts {
+        let start_line = part["start_line"].as_u64().unwrap() as usize;
+        let end_line = part["end_line"].as_u64().unwrap() as usize;
+        assert!(lines[start_line - 1].starts_with("####  "));
+        assert!(!lines[end_line - 1].is_empty());
+    }
+    let install = &parts[2];
+    assert_eq!(install["module"], "deps");
+    assert_eq!(install["source"]["kind"], "local");
+    assert_eq!(install["template_line"], 19);
+    assert_eq!(install["args"]["api_token"], "<redacted>");
+    assert_eq!(install["args"]["auth_token"], "<redacted>");
+    assert_eq!(install["args"]["author"], "Jane Doe");
+    assert_eq!(
+        lines[install["end_line"].as_u64().unwrap() as usize - 1],
+        "RUN echo version 1.2"
+    );
+    let template_lines: Vec<Option<u64>> = install["template_lines"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|line| line.as_u64())
+        .collect();
+    assert_eq!(
+        template_lines,
+        [
+            Some(19),
+            Some(20),
+            None,
+            Some(24),
+            None,
+            Some(26),
+            None,
+            Some(26),
+            None,
+            Some(28)
+        ]
+    );
+    fs::remove_dir_all(build_dir).unwrap();
+}
+
+#[test]
+fn explain_output_lines() {
+    let yard = |args: &[&str]| {
+        assert_cmd::Command::cargo_bin("yard")
+            .unwrap()
+            .current_dir("tests/provenance")
+            .args(args)
+            .assert()
+    };
+    yard(&["explain", ".", "out.Containerfile"])
+        .success()
+        .stdout(predicate::eq(
+            "'out.Containerfile' has 32 lines:
+  11-15  deps (build stage)  deps.md
+  17-19  inline_0  ~INLINE~
+  21-32  deps (install stage)  deps.md
+",
+        ));
+    yard(&["explain", ".", "out.Containerfile", "24"])
+        .success()
+        .stdout(predicate::eq(
+            "Line 24 of 'out.Containerfile':
+  Module: deps (install stage)
+  Source: deps.md
+  Lines: 21-32
+  Template line: 20
+  Args:
+    api_token=<redacted>
+    auth_token=<redacted>
+    author=Jane Doe
+    version=1.2
+",
+        ));
+    // Lines after `{% if %}` and `{% for %}` are traced, lines the tags generate are not
+    for (line, template_line) in [
+        ("26", "Template line: 24\n"),
+        ("30", "Template line: 26\n"),
+        ("32", "Template line: 28\n"),
+        (
+            "25",
+            "Template line: unknown, generated by the template block starting at line 19\n",
+        ),
+    ] {
+        yard(&["explain", ".", "out.Containerfile", line])
+            .success()
+            .stdout(predicate::str::contains(template_line));
+    }
+    yard(&["explain", ".", "out.Containerfile", "3"])
+        .success()
+        .stdout(predicate::str::contains("is not rendered from a module"));
+    yard(&["explain", ".", "out.Containerfile", "33"])
+        .failure()
+        .stderr(predicate::str::contains("has 32 lines"));
+    // Cache busting only changes the rendered outputs, so it needs one
+    yard(&["explain", ".", "--with-cache-busting"])
+        .failure()
+        .stderr(predicate::str::contains("<OUTPUT>"));
+}
+
+#[test]
+fn build_manifest() {
+    let dir = "tests/build_manifest";
+    let build_dir = format!("{dir}/build");
+    let _ = fs::remove_dir_all(&build_dir);
+    let yard = |args: &[&str]| {
+        assert_cmd::Command::cargo_bin("yard")
+            .unwrap()
+            .current_dir(dir)
+            .args(args)
+            .assert()
+    };
+    yard(&["build", "--emit-manifest", "json"]).success();
+    let manifest: serde_json::Value = serde_json::from_str(
+        &fs::read_to_string(format!("{build_dir}/yard-manifest.json")).unwrap(),
+    )
+    .unwrap();
+    let schema: serde_json::Value =
+        serde_json::from_str(&fs::read_to_string("src/schemas/yard-manifest-schema.json").unwrap())
+            .unwrap();
+    let validator = jsonschema::Validator::options()
+        .with_draft(jsonschema::Draft::Draft7)
+        .build(&schema)
+        .unwrap();
+    validator.validate(&manifest).unwrap();
+
+    assert_eq!(manifest["version"], 1);
+    let outputs = manifest["outputs"].as_array().unwrap();
+    let output_paths: Vec<&str> = outputs
+        .iter()
+        .map(|output| output["path"].as_str().unwrap())
+        .collect();
+    assert_eq!(
+        output_paths,
+        ["out.base.Containerfile", "app/out.app.Containerfile"]
+    );
+    let modules = outputs[1]["modules"].as_array().unwrap();
+    let deps = &modules[1];
+    assert_eq!(deps["source"]["kind"], "local");
+    assert_eq!(deps["source"]["path"], "deps.md");
+    assert_eq!(deps["stages"], serde_json::json!(["yard-deps"]));
+    let app = &modules[2];
+    assert_eq!(app["args"]["port"], "8080");
+    assert_eq!(app["args"]["api_token"], "<redacted>");
+    assert_eq!(app["required_files"], serde_json::json!(["app/app.conf"]));
+    assert_eq!(
+        manifest["required_files"],
+        serde_json::json!([{
+            "path": "app/app.conf",
+            "sha256": "37107a4e5ea873399e16cc41781ede69752273d4232675d990fda44a0603dfa2"
+        }])
+    );
+
+    // The manifest is a generated file, checked and removed like the outputs
+    yard(&["build", "--check", "--emit-manifest", "json"]).success();
+    yard(&["build"]).success().stdout(predicate::str::contains(
+        "yard-manifest.json', it is no longer generated",
+    ));
+    assert!(!fs::exists(format!("{build_dir}/yard-manifest.json")).unwrap());
+    fs::remove_dir_all(build_dir).unwrap();
+}
+
+#[test]
+fn oci_labels() {
+    let dir = "tests/oci_labels";
+    let build_dir = format!("{dir}/build");
+    let _ = fs::remove_dir_all(&build_dir);
+    assert_cmd::Command::cargo_bin("yard")
+        .unwrap()
+        .current_dir(dir)
+        .args(["build", "--profile", "ci"])
+        .assert()
+        .success();
+    let output = fs::read_to_string(format!("{build_dir}/out.Containerfile")).unwrap();
+    assert!(output.ends_with(&format!(
+        r#"ENTRYPOINT ["/usr/local/bin/tool"]
+
+####  ~LABELS~  ####
+
+LABEL org.opencontainers.image.title="out.Containerfile" \
+      io.containeryard.version="{}" \
+      io.containeryard.profile="ci" \
+      io.containeryard.modules="tool"
+"#,
+        env!("CARGO_PKG_VERSION")
+    )));
+    // The labels are in the final stage, not in the hoisted stage of the module
+    let label_line = output.find("LABEL").unwrap();
+    let last_from = output.rfind("\nFROM ").unwrap();
+    assert!(output[last_from..].starts_with("\nFROM alpine:3.20\n"));
+    assert!(last_from < label_line);
+    fs::remove_dir_all(build_dir).unwrap();
+}